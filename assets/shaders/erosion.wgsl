@@ -6,6 +6,13 @@ var map: texture_storage_2d<rgba32float, read_write>;
 var<storage, read> brush_indices: array<vec2<i32>, 64>;
 @group(0) @binding(3)
 var<storage, read> brush_weights: array<f32, 64>;
+@group(0) @binding(4)
+var erosion_mask: texture_storage_2d<r32float, read_write>;
+
+@group(1) @binding(0)
+var<uniform> batch: ErosionBatch;
+
+const MASK_SPAWN_ATTEMPTS: u32 = 16u;
 
 struct MountainSettings {
     map_size: u32,
@@ -19,7 +26,7 @@ struct MountainSettings {
     strength: f32,
     center: vec2<f32>,
 
-    time: f32,
+    seed: u32,
     brush_length: u32,
 
     sun_direction: vec3<f32>,
@@ -36,7 +43,22 @@ struct MountainSettings {
     start_speed: f32,
     start_water: f32,
 
-    _padding: vec2<f32>,
+    iteration: u32,
+    sun_size: f32,
+    shadow_steps: u32,
+    shadow_step_scale: f32,
+
+    ao_directions: u32,
+    ao_steps: u32,
+    ao_radius: f32,
+    ao_height_scale: f32,
+
+    masked_erosion: u32,
+}
+
+struct ErosionBatch {
+    first_droplet: u32,
+    droplets: u32,
 }
 
 // https://www.shadertoy.com/view/4djSRW
@@ -64,9 +86,50 @@ fn get_height_gradient(pos: vec2<f32>) -> vec3<f32> {
     return vec3(height, gx, gy);
 }
 
+// https://www.pcg-random.org, integer so large droplet indices keep their precision.
+fn pcg(v: u32) -> u32 {
+    let state = v * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+fn spawn_position(droplet: u32, attempt: u32) -> vec2<f32> {
+    let x = pcg(droplet ^ pcg(settings.seed + attempt * 0x9e3779b9u));
+    let y = pcg(x);
+    return vec2(f32(x), f32(y)) / 4294967296.0 * f32(settings.map_size);
+}
+
+fn mask_at(pos: vec2<f32>) -> f32 {
+    let size = vec2<f32>(textureDimensions(erosion_mask));
+    return textureLoad(erosion_mask, vec2<i32>(pos * size / f32(settings.map_size))).x;
+}
+
 @compute @workgroup_size(1, 64, 1)
 fn erode(@builtin(global_invocation_id) id: vec3<u32>) {
-    var pos = hash23(vec3(vec2<f32>(id.xy), settings.time)) * f32(settings.map_size);
+    let local = id.x * 64u + id.y;
+    if local >= batch.droplets {
+        return;
+    }
+
+    let droplet = batch.first_droplet + local;
+    var pos = spawn_position(droplet, 0u);
+
+    // Rejection sample the mask, droplets that find no painted spot are dropped.
+    if settings.masked_erosion != 0u {
+        var accepted = false;
+        for (var attempt = 0u; attempt < MASK_SPAWN_ATTEMPTS; attempt++) {
+            pos = spawn_position(droplet, attempt);
+            if hash23(vec3(pos, f32(attempt) + 0.5)).x < mask_at(pos) {
+                accepted = true;
+                break;
+            }
+        }
+
+        if !accepted {
+            return;
+        }
+    }
+
     var dir = vec2(0.0);
     var speed = settings.start_speed;
     var water = settings.start_water;
@@ -105,10 +168,10 @@ fn erode(@builtin(global_invocation_id) id: vec3<u32>) {
             let n3 = textureLoad(map, node + vec2(0, 1));
             let n4 = textureLoad(map, node + vec2(1, 1));
 
-            textureStore(map, node, vec4(n1.x + amount * (1.0 - cell_offset.x) * (1.0 - cell_offset.y), n1.yzw));
-            textureStore(map, node + vec2(1, 0), vec4(n2.x + amount * cell_offset.x * (1.0 - cell_offset.y), n2.yzw));
-            textureStore(map, node + vec2(0, 1), vec4(n3.x + amount * (1.0 - cell_offset.x) * cell_offset.y, n3.yzw));
-            textureStore(map, node + vec2(1, 1), vec4(n4.x + amount * cell_offset.x * cell_offset.y, n4.yzw));
+            textureStore(map, node, vec4(n1.x + amount * (1.0 - cell_offset.x) * (1.0 - cell_offset.y), n1.yz, n1.w + amount * (1.0 - cell_offset.x) * (1.0 - cell_offset.y)));
+            textureStore(map, node + vec2(1, 0), vec4(n2.x + amount * cell_offset.x * (1.0 - cell_offset.y), n2.yz, n2.w + amount * cell_offset.x * (1.0 - cell_offset.y)));
+            textureStore(map, node + vec2(0, 1), vec4(n3.x + amount * (1.0 - cell_offset.x) * cell_offset.y, n3.yz, n3.w + amount * (1.0 - cell_offset.x) * cell_offset.y));
+            textureStore(map, node + vec2(1, 1), vec4(n4.x + amount * cell_offset.x * cell_offset.y, n4.yz, n4.w + amount * cell_offset.x * cell_offset.y));
         } else {
             let amount = min((sediment_capacity - sediment) * settings.erode_speed, -delta_height);
 
@@ -121,7 +184,7 @@ fn erode(@builtin(global_invocation_id) id: vec3<u32>) {
                 let h = textureLoad(map, erode_pos);
                 let delta_sediment = min(weighted_amount, h.x);
 
-                textureStore(map, erode_pos, vec4(h.x - delta_sediment, h.yzw));
+                textureStore(map, erode_pos, vec4(h.x - delta_sediment, h.yz, h.w - delta_sediment));
                 sediment += delta_sediment;
             }
         }
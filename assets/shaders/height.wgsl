@@ -1,11 +1,9 @@
 @group(0) @binding(0)
 var<uniform> settings: MountainSettings;
 @group(0) @binding(1)
-var map: texture_storage_2d<rgba32float, read_write>;
+var source: texture_2d<f32>;
 @group(0) @binding(2)
-var<storage, read> brush_indices: array<vec2<i32>, 64>;
-@group(0) @binding(3)
-var<storage, read> brush_weights: array<f32, 64>;
+var destination: texture_storage_2d<rgba32float, write>;
 
 struct MountainSettings {
     map_size: u32,
@@ -19,7 +17,7 @@ struct MountainSettings {
     strength: f32,
     center: vec2<f32>,
 
-    time: f32,
+    seed: u32,
     brush_length: u32,
 
     sun_direction: vec3<f32>,
@@ -36,7 +34,17 @@ struct MountainSettings {
     start_speed: f32,
     start_water: f32,
 
-    _padding: vec2<f32>,
+    iteration: u32,
+    sun_size: f32,
+    shadow_steps: u32,
+    shadow_step_scale: f32,
+
+    ao_directions: u32,
+    ao_steps: u32,
+    ao_radius: f32,
+    ao_height_scale: f32,
+
+    masked_erosion: u32,
 };
 
 var<private> perm: array<i32, 256> = array(
@@ -180,23 +188,26 @@ fn height(@builtin(global_invocation_id) id: vec3<u32>) {
 
     height = clamp(height * settings.strength + settings.offset, 0.0, 1.0);
 
-    textureStore(map, id.xy, vec4(height, 0.0, 0.0, 1.0));
+    // z is sky visibility, fully visible until the occlusion bake runs.
+    // w accumulates net erosion (negative) and deposition (positive).
+    textureStore(destination, id.xy, vec4(height, 0.0, 1.0, 0.0));
 }
 
 @compute @workgroup_size(8, 8, 1)
 fn shadow(@builtin(global_invocation_id) id: vec3<u32>) {
     let uv = vec2<f32>(id.xy) / f32(settings.map_size);
 
-    let original = textureLoad(map, id.xy);
+    let original = textureLoad(source, id.xy, 0);
     let height = original.x;
     let pixel_size = 1.0 / f32(settings.map_size);
-    var shadow = 0.0;
-    var pos = vec3(uv.x, height, uv.y);
-    var n = 0;
-
-    for (var i = 0u; i < 128u; i++) {
-        n++;
-
+    let start = vec3(uv.x, height, uv.y);
+    let penumbra = tan(max(settings.sun_size, 1e-4));
+    var pos = start;
+    // Smallest clearance to distance ratio seen, relative to the sun's angular size.
+    var lit = 1.0;
+
+    // A march that runs out of steps counts as lit, only the terrain it passed shades it.
+    for (var i = 0u; i < settings.shadow_steps; i++) {
         let coord = pos.xz * f32(settings.map_size);
 
         if coord.x >= f32(settings.map_size) - f32(settings.erosion_radius)
@@ -205,22 +216,55 @@ fn shadow(@builtin(global_invocation_id) id: vec3<u32>) {
             break;
         }
 
-        let h = textureLoad(map, vec2<u32>(coord)).x;
-        if h > pos.y {
-            shadow = 1.0;
-            break;
+        let h = textureLoad(source, vec2<u32>(coord), 0).x;
+        let clearance = pos.y - h;
+        let dist = distance(pos, start);
+        if dist > 0.0 {
+            lit = min(lit, clearance / (dist * penumbra));
         }
 
-        if pos.y > 1.0 {
+        if lit <= -1.0 || pos.y > 1.0 {
             break;
         }
 
-        pos += settings.sun_direction * max((pos.y - h) * 0.05, pixel_size);
+        pos += settings.sun_direction * max(clearance * settings.shadow_step_scale, pixel_size);
     }
 
-    if n == 128 {
-        shadow = 1.0;
+    let shadow = 1.0 - clamp(0.5 + 0.5 * lit, 0.0, 1.0);
+
+    textureStore(destination, id.xy, vec4(height, shadow, original.zw));
+}
+
+// Fraction of the sky hemisphere visible above the horizon, averaged over several directions.
+@compute @workgroup_size(8, 8, 1)
+fn ambient_occlusion(@builtin(global_invocation_id) id: vec3<u32>) {
+    let original = textureLoad(source, id.xy, 0);
+    let height = original.x;
+    let size = i32(settings.map_size);
+    var visibility = 0.0;
+
+    for (var d = 0u; d < settings.ao_directions; d++) {
+        let angle = (f32(d) + 0.5) / f32(settings.ao_directions) * 6.28318530718;
+        let dir = vec2(cos(angle), sin(angle));
+        // Sine of the highest elevation angle seen so far.
+        var horizon = 0.0;
+
+        for (var s = 1u; s <= settings.ao_steps; s++) {
+            let t = f32(s) / f32(settings.ao_steps);
+            let dist = max(t * t * settings.ao_radius, 1.0);
+            let coord = vec2<i32>(vec2<f32>(id.xy) + dir * dist);
+
+            if coord.x < 0 || coord.y < 0 || coord.x >= size || coord.y >= size {
+                break;
+            }
+
+            let rise = (textureLoad(source, coord, 0).x - height) * settings.ao_height_scale * f32(size);
+            horizon = max(horizon, rise / length(vec2(rise, dist)));
+        }
+
+        visibility += 1.0 - horizon;
     }
 
-    textureStore(map, id.xy, vec4(height, clamp(shadow, 0.0, 1.0), original.zw));
+    textureStore(destination, id.xy, vec4(original.xy, visibility / f32(settings.ao_directions), original.w));
 }
+
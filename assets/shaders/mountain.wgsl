@@ -1,7 +1,17 @@
-#import bevy_pbr::{
-    mesh_functions,
-    skinning,
-    view_transformations::position_world_to_clip,
+#import mountain::terrain::{
+    settings,
+    map,
+    map_sampler,
+    world_to_uv,
+    terrain_vertex,
+    terrain_surface,
+    height_normal,
+    gradient,
+    outside_border,
+    view_mode_color,
+    border_tint,
+    apply_overlays,
+    apply_fog,
 }
 
 #ifdef PREPASS_PIPELINE
@@ -10,64 +20,9 @@
 #import bevy_pbr::forward_io::{Vertex, VertexOutput}
 #endif
 
-@group(2) @binding(0)
-var<uniform> settings: MountainRenderSettings;
-@group(2) @binding(1)
-var map: texture_2d<f32>;
-@group(2) @binding(2)
-var map_sampler: sampler;
-@group(2) @binding(3)
-var<storage, read> colors: array<ColorEntry>;
-
-struct ColorEntry {
-    color: vec4<f32>,
-    elevation: f32,
-    steepness: f32,
-    _padding: vec2<f32>,
-}
-
-struct MountainRenderSettings {
-    sun_direction: vec3<f32>,
-    terrain_height: f32,
-    blend_sharpness: f32,
-    pixel_size: f32,
-
-    normal_strength: f32,
-    erosion_radius: i32,
-}
-
-
 @vertex
 fn vertex(vertex: Vertex) -> VertexOutput {
-    let uv = vertex.uv;
-    let sample = textureSampleLevel(map, map_sampler, uv, 0.0);
-    let height = sample.x;
-
-#ifdef SKINNED
-    var model = skinning::skin_model(vertex.joint_indices, vertex.joint_weights);
-#else
-    var model = mesh_functions::get_model_matrix(vertex.instance_index);
-#endif
-
-    let position = vertex.position + vec3(0.0, height * settings.terrain_height, 0.0);
-    var out: VertexOutput;
-
-    out.world_position = mesh_functions::mesh_position_local_to_world(model, vec4<f32>(position, 1.0));
-    out.position = position_world_to_clip(out.world_position.xyz);
-
-    out.uv = vertex.uv;
-
-#ifdef VERTEX_COLORS
-    out.color = vertex.color;
-#endif
-
-#ifdef VERTEX_OUTPUT_INSTANCE_INDEX
-    out.instance_index = vertex.instance_index;
-#endif
-
-    out.world_normal = vec3(0.0, 1.0, 0.0);
-
-    return out;
+    return terrain_vertex(vertex);
 }
 
 fn hash(p: vec2<f32>) -> f32 {
@@ -78,48 +33,15 @@ fn hash(p: vec2<f32>) -> f32 {
     return f32(n) * (1.0 / f32(0xffffffffu));
 }
 
-fn terrain_color(h: f32, normal: vec3<f32>) -> vec3<f32> {
-    let steepness = 1.0 - dot(normal, vec3(0.0, 1.0, 0.0));
-    let pos = vec2(h, steepness);
-    var col = vec3(0.0);
-    var amount = 0.0;
-
-    for (var i = 0u; i < 7; i++) {
-        let entry = colors[i];
-        let position = vec2(entry.elevation, entry.steepness);
-        let dist = distance(pos, position);
-        let weight = 1.0 / pow(dist, settings.blend_sharpness);
-
-        col += weight * entry.color.rgb * entry.color.a;
-        amount += weight * entry.color.a;
-    }
-
-    col /= amount;
-
-    return col;
-}
-
-fn gradient(uv: vec2<f32>) -> vec2<f32> {
-    let north = textureSample(map, map_sampler, uv + vec2(0.0, settings.pixel_size)).x;
-    let south = textureSample(map, map_sampler, uv + vec2(0.0, -settings.pixel_size)).x;
-    let east = textureSample(map, map_sampler, uv + vec2(settings.pixel_size, 0.0)).x;
-    let west = textureSample(map, map_sampler, uv + vec2(-settings.pixel_size, 0.0)).x;
-
-    return vec2((east - west) / (2.0 * settings.pixel_size), (south - north) / (2.0 * settings.pixel_size));
-}
-
 @fragment
 fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
-    let uv = in.uv;
-    let map_size = 1.0 / settings.pixel_size;
-    let coord = uv * map_size;
+    let uv = world_to_uv(in.world_position.xz);
     let sample = textureSample(map, map_sampler, uv);
     let terrain_height = sample.x;
     var shadow = sample.y;
 
-    if coord.x >= map_size - f32(settings.erosion_radius)
-    || coord.y >= map_size - f32(settings.erosion_radius)
-    || coord.x < f32(settings.erosion_radius) || coord.y < f32(settings.erosion_radius) {
+    let border = outside_border(uv);
+    if border && settings.show_border == 0u {
         discard;
     }
 
@@ -127,11 +49,29 @@ fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
     let normal = normalize(vec3(grad.x, 1.0, grad.y));
     let sun = normalize(settings.sun_direction * vec3(1.0, settings.normal_strength, 1.0));
 
-    shadow = max(shadow, max(dot(normal, sun) * 0.5 + 0.5, 0.0));
-    shadow = min(shadow, 0.9);
+    let world_normal = height_normal(in.world_position.xz);
+    let surface = terrain_surface(in.world_position.xyz, world_normal, terrain_height, normal);
+
+    // Layer normal detail moved into the gradient normal, which is in normalized height per uv.
+    let detail = (surface.normal.xz - world_normal.xz) * settings.world_size / settings.terrain_height;
+    let shading_normal = normalize(normal - vec3(detail.x, 0.0, detail.y));
 
-    var col = terrain_color(terrain_height, normal);
+    shadow = max(shadow, max(dot(shading_normal, sun) * 0.5 + 0.5, 0.0));
+    let occlusion = mix(1.0, sample.z, settings.ao_strength);
+    shadow = min(shadow, 1.0 - settings.ambient * occlusion);
+
+    var col = surface.albedo;
     col = mix(col, vec3(0.0), shadow);
 
+    if settings.view_mode != 0u {
+        col = view_mode_color(in.world_position.xz, uv);
+    } else {
+        col = apply_fog(col, in.world_position.xyz);
+    }
+    col = apply_overlays(col, in.world_position.xyz);
+    if border {
+        col = border_tint(col, in.world_position.xz);
+    }
+
     return vec4(col, 1.0);
 }
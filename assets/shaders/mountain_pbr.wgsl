@@ -0,0 +1,80 @@
+#import bevy_pbr::{
+    pbr_fragment::pbr_input_from_standard_material,
+    pbr_functions::alpha_discard,
+}
+
+#import mountain::terrain::{
+    map,
+    map_sampler,
+    world_to_uv,
+    terrain_surface,
+    height_normal,
+    gradient,
+    outside_border,
+    view_mode_color,
+    border_tint,
+    apply_overlays,
+    apply_fog,
+    settings,
+}
+
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::{
+    prepass_io::{VertexOutput, FragmentOutput},
+    pbr_deferred_functions::deferred_output,
+}
+#else
+#import bevy_pbr::{
+    forward_io::{VertexOutput, FragmentOutput},
+    pbr_functions::{apply_pbr_lighting, main_pass_post_lighting_processing},
+}
+#endif
+
+@fragment
+fn fragment(
+    in: VertexOutput,
+    @builtin(front_facing) is_front: bool,
+) -> FragmentOutput {
+    let uv = world_to_uv(in.world_position.xz);
+    let border = outside_border(uv);
+    if border && settings.show_border == 0u {
+        discard;
+    }
+
+    var pbr_input = pbr_input_from_standard_material(in, is_front);
+
+    let normal = height_normal(in.world_position.xz);
+    let sample = textureSample(map, map_sampler, uv);
+    // The palette's steepness is in normalized height per uv, like the unlit shader.
+    let grad = gradient(uv);
+    let steepness_normal = normalize(vec3(grad.x, 1.0, grad.y));
+    let surface = terrain_surface(in.world_position.xyz, normal, sample.x, steepness_normal);
+
+    pbr_input.material.base_color = vec4(surface.albedo, pbr_input.material.base_color.a);
+    pbr_input.material.perceptual_roughness = surface.roughness;
+    pbr_input.world_normal = normal;
+    pbr_input.N = surface.normal;
+    pbr_input.diffuse_occlusion = vec3(mix(1.0, sample.z, settings.ao_strength));
+
+    pbr_input.material.base_color = alpha_discard(pbr_input.material, pbr_input.material.base_color);
+
+#ifdef PREPASS_PIPELINE
+    let out = deferred_output(in, pbr_input);
+#else
+    var out: FragmentOutput;
+    out.color = apply_pbr_lighting(pbr_input);
+    out.color = main_pass_post_lighting_processing(pbr_input, out.color);
+
+    if settings.view_mode != 0u {
+        out.color = vec4(view_mode_color(in.world_position.xz, uv), 1.0);
+    } else {
+        out.color = vec4(apply_fog(out.color.rgb, in.world_position.xyz), out.color.a);
+    }
+    out.color = vec4(apply_overlays(out.color.rgb, in.world_position.xyz), out.color.a);
+    if border {
+        out.color = vec4(border_tint(out.color.rgb, in.world_position.xz), out.color.a);
+    }
+#endif
+
+    return out;
+}
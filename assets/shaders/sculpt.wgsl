@@ -0,0 +1,198 @@
+@group(0) @binding(0)
+var<uniform> stamp: SculptStamp;
+@group(0) @binding(1)
+var source: texture_2d<f32>;
+@group(0) @binding(2)
+var<storage, read_write> pick_result: vec4<f32>;
+@group(0) @binding(3)
+var erosion_mask: texture_storage_2d<r32float, read_write>;
+@group(0) @binding(4)
+var destination: texture_storage_2d<rgba32float, write>;
+
+struct SculptStamp {
+    ray_origin: vec3<f32>,
+    world_size: f32,
+    ray_direction: vec3<f32>,
+    terrain_height: f32,
+    brush: u32,
+    radius: f32,
+    strength: f32,
+    falloff: f32,
+    noise_scale: f32,
+    seed: u32,
+    pick: u32,
+    apply: u32,
+}
+
+const BRUSH_RAISE: u32 = 0u;
+const BRUSH_LOWER: u32 = 1u;
+const BRUSH_SMOOTH: u32 = 2u;
+const BRUSH_FLATTEN: u32 = 3u;
+const BRUSH_NOISE: u32 = 4u;
+const BRUSH_PINCH: u32 = 5u;
+const BRUSH_EROSION_MASK: u32 = 6u;
+const BRUSH_ERASE_EROSION_MASK: u32 = 7u;
+
+const MAX_PICK_STEPS: u32 = 1024u;
+
+fn load_height(texel: vec2<i32>) -> f32 {
+    let size = vec2<i32>(textureDimensions(source));
+    return textureLoad(source, clamp(texel, vec2(0), size - 1), 0).x;
+}
+
+// Bilinear height at a texel space position.
+fn height_at(pos: vec2<f32>) -> f32 {
+    let base = floor(pos - 0.5);
+    let f = pos - 0.5 - base;
+    let coord = vec2<i32>(base);
+
+    let top = mix(load_height(coord), load_height(coord + vec2(1, 0)), f.x);
+    let bottom = mix(load_height(coord + vec2(0, 1)), load_height(coord + vec2(1, 1)), f.x);
+    return mix(top, bottom, f.y);
+}
+
+fn world_to_texel(world: vec2<f32>) -> vec2<f32> {
+    return (world / stamp.world_size + 0.5) * f32(textureDimensions(source).x);
+}
+
+fn surface_height(world: vec2<f32>) -> f32 {
+    return height_at(world_to_texel(world)) * stamp.terrain_height;
+}
+
+// Steps proportionally to the clearance above the surface, then bisects the last step.
+@compute @workgroup_size(1, 1, 1)
+fn pick() {
+    let half_size = stamp.world_size * 0.5;
+    let texel_size = stamp.world_size / f32(textureDimensions(source).x);
+    let dir = stamp.ray_direction;
+
+    var t = 0.0;
+    if stamp.ray_origin.y > stamp.terrain_height {
+        if dir.y >= 0.0 {
+            pick_result = vec4(0.0);
+            return;
+        }
+        t = (stamp.terrain_height - stamp.ray_origin.y) / dir.y;
+    }
+
+    var prev_t = t;
+    for (var i = 0u; i < MAX_PICK_STEPS; i++) {
+        let p = stamp.ray_origin + dir * t;
+        if p.y < 0.0 || (dir.y > 0.0 && p.y > stamp.terrain_height) {
+            break;
+        }
+
+        let clearance = p.y - surface_height(p.xz);
+        if clearance <= 0.0 && all(abs(p.xz) <= vec2(half_size)) {
+            var lo = prev_t;
+            var hi = t;
+            for (var j = 0u; j < 8u; j++) {
+                let mid = (lo + hi) * 0.5;
+                let m = stamp.ray_origin + dir * mid;
+                if m.y > surface_height(m.xz) {
+                    lo = mid;
+                } else {
+                    hi = mid;
+                }
+            }
+
+            pick_result = vec4(stamp.ray_origin + dir * hi, 1.0);
+            return;
+        }
+
+        prev_t = t;
+        t += max(clearance * 0.5, texel_size);
+    }
+
+    pick_result = vec4(0.0);
+}
+
+fn hash(p: vec2<f32>) -> f32 {
+    var p3 = fract(vec3(p.xyx) * 0.1031);
+    p3 += dot(p3, p3.yzx + 33.33);
+    return fract((p3.x + p3.y) * p3.z);
+}
+
+fn value_noise(p: vec2<f32>) -> f32 {
+    let i = floor(p);
+    let f = p - i;
+    let u = f * f * (3.0 - 2.0 * f);
+
+    return mix(
+        mix(hash(i), hash(i + vec2(1.0, 0.0)), u.x),
+        mix(hash(i + vec2(0.0, 1.0)), hash(i + vec2(1.0, 1.0)), u.x),
+        u.y,
+    );
+}
+
+// New height of a texel under the brush, mask brushes paint the mask and keep the height.
+fn sculpt(texel: vec2<i32>, original: f32) -> f32 {
+    if pick_result.w == 0.0 {
+        return original;
+    }
+
+    let center = world_to_texel(pick_result.xz);
+    let offset = vec2<f32>(texel) + 0.5 - center;
+    let dist = length(offset) / stamp.radius;
+    if dist >= 1.0 {
+        return original;
+    }
+
+    let weight = 1.0 - smoothstep(1.0 - stamp.falloff, 1.0, dist);
+    // Blending brushes converge within a few seconds at the same strength that raises a few percent.
+    let blend = clamp(stamp.strength * weight * 10.0, 0.0, 1.0);
+
+    if stamp.brush == BRUSH_EROSION_MASK || stamp.brush == BRUSH_ERASE_EROSION_MASK {
+        // Only one of the heightmap texels covering each mask texel writes it.
+        let ratio = vec2<i32>(textureDimensions(source)) / vec2<i32>(textureDimensions(erosion_mask));
+        if all(texel % ratio == vec2(0)) {
+            let mask_texel = texel / ratio;
+            let mask = textureLoad(erosion_mask, mask_texel).x + select(-blend, blend, stamp.brush == BRUSH_EROSION_MASK);
+            textureStore(erosion_mask, mask_texel, vec4(clamp(mask, 0.0, 1.0)));
+        }
+        return original;
+    }
+
+    var height = original;
+
+    switch stamp.brush {
+        case BRUSH_RAISE: {
+            height += stamp.strength * weight;
+        }
+        case BRUSH_LOWER: {
+            height -= stamp.strength * weight;
+        }
+        case BRUSH_SMOOTH: {
+            let spread = max(stamp.radius / 8.0, 1.0);
+            var sum = 0.0;
+            for (var y = -1; y <= 1; y++) {
+                for (var x = -1; x <= 1; x++) {
+                    sum += height_at(vec2<f32>(texel) + 0.5 + vec2(f32(x), f32(y)) * spread);
+                }
+            }
+            height = mix(height, sum / 9.0, blend);
+        }
+        case BRUSH_FLATTEN: {
+            height = mix(height, pick_result.y / stamp.terrain_height, blend);
+        }
+        case BRUSH_NOISE: {
+            let noise = value_noise(vec2<f32>(texel) * stamp.noise_scale + f32(stamp.seed % 1024u) * 17.0);
+            height += (noise * 2.0 - 1.0) * stamp.strength * weight;
+        }
+        case BRUSH_PINCH: {
+            // Pull heights from further out so features gather towards the centre.
+            height = mix(height, height_at(center + offset * (1.0 + blend)), blend);
+        }
+        default: {}
+    }
+
+    return max(height, 0.0);
+}
+
+// Dispatched over the whole map so every texel of the destination is written.
+@compute @workgroup_size(8, 8, 1)
+fn apply(@builtin(global_invocation_id) id: vec3<u32>) {
+    let texel = vec2<i32>(id.xy);
+    let original = textureLoad(source, texel, 0);
+    textureStore(destination, texel, vec4(sculpt(texel, original.x), original.yzw));
+}
@@ -0,0 +1,12 @@
+#import bevy_pbr::{
+    forward_io::VertexOutput,
+    mesh_view_bindings::view,
+}
+
+#import mountain::terrain::sky_color
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    let dir = normalize(in.world_position.xyz - view.world_position);
+    return vec4(sky_color(dir, true), 1.0);
+}
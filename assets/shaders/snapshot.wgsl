@@ -0,0 +1,20 @@
+@group(0) @binding(0)
+var map: texture_storage_2d<rgba32float, read_write>;
+@group(0) @binding(1)
+var snapshot: texture_storage_2d<rg32float, write>;
+@group(0) @binding(2)
+var source: texture_2d<f32>;
+
+// Only height and the erosion accumulator are kept, shadow and occlusion are re-baked on restore.
+@compute @workgroup_size(8, 8, 1)
+fn save(@builtin(global_invocation_id) id: vec3<u32>) {
+    let original = textureLoad(map, id.xy);
+    textureStore(snapshot, id.xy, vec4(original.x, original.w, 0.0, 0.0));
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn restore(@builtin(global_invocation_id) id: vec3<u32>) {
+    let saved = textureLoad(source, id.xy, 0);
+    let original = textureLoad(map, id.xy);
+    textureStore(map, id.xy, vec4(saved.x, original.yz, saved.y));
+}
@@ -0,0 +1,414 @@
+#define_import_path mountain::terrain
+
+#import bevy_pbr::{
+    mesh_functions,
+    mesh_view_bindings::view,
+    view_transformations::position_world_to_clip,
+}
+
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::prepass_io::{Vertex, VertexOutput}
+#else
+#import bevy_pbr::forward_io::{Vertex, VertexOutput}
+#endif
+
+#ifdef MOUNTAIN_EXTENSION
+@group(2) @binding(100)
+var<uniform> settings: MountainRenderSettings;
+@group(2) @binding(101)
+var map: texture_2d<f32>;
+@group(2) @binding(102)
+var map_sampler: sampler;
+@group(2) @binding(103)
+var<storage, read> colors: array<ColorEntry>;
+@group(2) @binding(104)
+var albedo_layers: texture_2d_array<f32>;
+@group(2) @binding(105)
+var albedo_sampler: sampler;
+@group(2) @binding(106)
+var normal_layers: texture_2d_array<f32>;
+@group(2) @binding(107)
+var normal_sampler: sampler;
+@group(2) @binding(108)
+var roughness_layers: texture_2d_array<f32>;
+@group(2) @binding(109)
+var roughness_sampler: sampler;
+@group(2) @binding(110)
+var erosion_mask: texture_2d<f32>;
+#else
+@group(2) @binding(0)
+var<uniform> settings: MountainRenderSettings;
+@group(2) @binding(1)
+var map: texture_2d<f32>;
+@group(2) @binding(2)
+var map_sampler: sampler;
+@group(2) @binding(3)
+var<storage, read> colors: array<ColorEntry>;
+@group(2) @binding(4)
+var albedo_layers: texture_2d_array<f32>;
+@group(2) @binding(5)
+var albedo_sampler: sampler;
+@group(2) @binding(6)
+var normal_layers: texture_2d_array<f32>;
+@group(2) @binding(7)
+var normal_sampler: sampler;
+@group(2) @binding(8)
+var roughness_layers: texture_2d_array<f32>;
+@group(2) @binding(9)
+var roughness_sampler: sampler;
+@group(2) @binding(10)
+var erosion_mask: texture_2d<f32>;
+#endif
+
+struct ColorEntry {
+    color: vec4<f32>,
+    elevation: f32,
+    steepness: f32,
+    roughness: f32,
+    layer: i32,
+}
+
+struct MountainRenderSettings {
+    sun_direction: vec3<f32>,
+    terrain_height: f32,
+    blend_sharpness: f32,
+    pixel_size: f32,
+
+    normal_strength: f32,
+    erosion_radius: i32,
+    world_size: f32,
+    lod_distance: f32,
+
+    morph_start: f32,
+    patch_resolution: u32,
+    texture_scale: f32,
+    triplanar_sharpness: f32,
+
+    layer_count: u32,
+    view_mode: u32,
+    show_border: u32,
+    show_erosion_mask: u32,
+
+    contours: u32,
+    contour_interval: f32,
+    index_contour_every: u32,
+    grid: u32,
+
+    grid_spacing: f32,
+    line_width: f32,
+
+    sky_zenith: vec3<f32>,
+    fog_density: f32,
+    sky_horizon: vec3<f32>,
+    fog_height_falloff: f32,
+    sun_color: vec3<f32>,
+    sun_disc_size: f32,
+    ambient: f32,
+    ao_strength: f32,
+}
+
+struct TerrainSurface {
+    albedo: vec3<f32>,
+    normal: vec3<f32>,
+    roughness: f32,
+}
+
+// Texture coordinates and their screen derivatives for the x, y and z facing projections.
+struct Triplanar {
+    uv_x: vec2<f32>,
+    uv_y: vec2<f32>,
+    uv_z: vec2<f32>,
+    ddx: vec3<f32>,
+    ddy: vec3<f32>,
+    weights: vec3<f32>,
+}
+
+
+fn world_to_uv(world: vec2<f32>) -> vec2<f32> {
+    return world / settings.world_size + 0.5;
+}
+
+fn sample_height(world: vec2<f32>) -> f32 {
+    return textureSampleLevel(map, map_sampler, world_to_uv(world), 0.0).x * settings.terrain_height;
+}
+
+// Patches are a unit grid scaled to their quadtree node, odd vertices slide onto the
+// parent grid as the camera approaches the edge of the node's lod range.
+fn morph_vertex(local: vec2<f32>, model: mat4x4<f32>) -> vec2<f32> {
+    let patch_size = length(model[0].xyz);
+    let resolution = f32(settings.patch_resolution);
+
+    let world = mesh_functions::mesh_position_local_to_world(model, vec4(local.x, 0.0, local.y, 1.0)).xz;
+    let dist = distance(view.world_position, vec3(world.x, sample_height(world), world.y));
+    let range = 2.0 * patch_size * settings.lod_distance;
+    let morph = clamp((dist / range - settings.morph_start) / (1.0 - settings.morph_start), 0.0, 1.0);
+
+    let grid = (local + 0.5) * resolution;
+    return (grid - fract(grid * 0.5) * 2.0 * morph) / resolution - 0.5;
+}
+
+// Normal of the displaced surface in world space, from central differences one texel apart.
+fn height_normal(world: vec2<f32>) -> vec3<f32> {
+    let texel = settings.world_size * settings.pixel_size;
+    let east = sample_height(world + vec2(texel, 0.0));
+    let west = sample_height(world - vec2(texel, 0.0));
+    let north = sample_height(world + vec2(0.0, texel));
+    let south = sample_height(world - vec2(0.0, texel));
+
+    return normalize(vec3(west - east, 2.0 * texel, south - north));
+}
+
+fn terrain_vertex(vertex: Vertex) -> VertexOutput {
+    var model = mesh_functions::get_model_matrix(vertex.instance_index);
+
+    let local = morph_vertex(vertex.position.xz, model);
+    let world = mesh_functions::mesh_position_local_to_world(model, vec4(local.x, 0.0, local.y, 1.0));
+    var out: VertexOutput;
+
+    out.world_position = vec4(world.x, sample_height(world.xz), world.z, 1.0);
+    out.position = position_world_to_clip(out.world_position.xyz);
+
+#ifdef DEPTH_CLAMP_ORTHO
+    out.clip_position_unclamped = out.position;
+    out.position.z = min(out.position.z, 1.0);
+#endif
+
+#ifdef MOTION_VECTOR_PREPASS
+    out.previous_world_position = out.world_position;
+#endif
+
+#ifdef VERTEX_COLORS
+    out.color = vertex.color;
+#endif
+
+#ifdef VERTEX_OUTPUT_INSTANCE_INDEX
+    out.instance_index = vertex.instance_index;
+#endif
+
+#ifdef PREPASS_PIPELINE
+#ifdef NORMAL_PREPASS_OR_DEFERRED_PREPASS
+    out.world_normal = height_normal(world.xz);
+#endif
+#else
+    out.world_normal = height_normal(world.xz);
+#endif
+
+    return out;
+}
+
+fn triplanar(world: vec3<f32>, normal: vec3<f32>) -> Triplanar {
+    let p = world / settings.texture_scale;
+    let weights = pow(abs(normal), vec3(settings.triplanar_sharpness));
+
+    var t: Triplanar;
+    t.uv_x = p.zy;
+    t.uv_y = p.xz;
+    t.uv_z = p.xy;
+    t.ddx = dpdx(p);
+    t.ddy = dpdy(p);
+    t.weights = weights / (weights.x + weights.y + weights.z);
+    return t;
+}
+
+fn sample_triplanar(layers: texture_2d_array<f32>, layer_sampler: sampler, layer: i32, t: Triplanar) -> vec4<f32> {
+    let x = textureSampleGrad(layers, layer_sampler, t.uv_x, layer, t.ddx.zy, t.ddy.zy);
+    let y = textureSampleGrad(layers, layer_sampler, t.uv_y, layer, t.ddx.xz, t.ddy.xz);
+    let z = textureSampleGrad(layers, layer_sampler, t.uv_z, layer, t.ddx.xy, t.ddy.xy);
+    return x * t.weights.x + y * t.weights.y + z * t.weights.z;
+}
+
+// Whiteout blend of the three tangent space normals onto the surface normal.
+fn triplanar_normal(layer: i32, t: Triplanar, normal: vec3<f32>) -> vec3<f32> {
+    var x = textureSampleGrad(normal_layers, normal_sampler, t.uv_x, layer, t.ddx.zy, t.ddy.zy).xyz * 2.0 - 1.0;
+    var y = textureSampleGrad(normal_layers, normal_sampler, t.uv_y, layer, t.ddx.xz, t.ddy.xz).xyz * 2.0 - 1.0;
+    var z = textureSampleGrad(normal_layers, normal_sampler, t.uv_z, layer, t.ddx.xy, t.ddy.xy).xyz * 2.0 - 1.0;
+
+    x = vec3(x.xy + normal.zy, abs(x.z) * normal.x);
+    y = vec3(y.xy + normal.xz, abs(y.z) * normal.y);
+    z = vec3(z.xy + normal.xy, abs(z.z) * normal.z);
+
+    return normalize(x.zyx * t.weights.x + y.xzy * t.weights.y + z.xyz * t.weights.z);
+}
+
+// Palette entries blended by distance in elevation and steepness. Entries with a texture
+// layer are sampled triplanar so steep faces don't stretch. `normal` drives the palette
+// weights, `world_normal` the projection.
+fn terrain_surface(world: vec3<f32>, world_normal: vec3<f32>, h: f32, normal: vec3<f32>) -> TerrainSurface {
+    let steepness = 1.0 - dot(normal, vec3(0.0, 1.0, 0.0));
+    let pos = vec2(h, steepness);
+    let t = triplanar(world, world_normal);
+
+    var surface = TerrainSurface(vec3(0.0), vec3(0.0), 0.0);
+    var amount = 0.0;
+
+    for (var i = 0u; i < arrayLength(&colors); i++) {
+        let entry = colors[i];
+        let position = vec2(entry.elevation, entry.steepness);
+        let dist = distance(pos, position);
+        let weight = 1.0 / pow(dist, settings.blend_sharpness) * entry.color.a;
+
+        var albedo = entry.color.rgb;
+        var layer_normal = world_normal;
+        var roughness = entry.roughness;
+
+        if entry.layer >= 0 && entry.layer < i32(settings.layer_count) {
+            albedo = sample_triplanar(albedo_layers, albedo_sampler, entry.layer, t).rgb;
+            layer_normal = triplanar_normal(entry.layer, t, world_normal);
+            roughness = sample_triplanar(roughness_layers, roughness_sampler, entry.layer, t).r;
+        }
+
+        surface.albedo += weight * albedo;
+        surface.normal += weight * layer_normal;
+        surface.roughness += weight * roughness;
+        amount += weight;
+    }
+
+    surface.albedo /= amount;
+    surface.normal = normalize(surface.normal);
+    surface.roughness /= amount;
+
+    return surface;
+}
+
+fn gradient(uv: vec2<f32>) -> vec2<f32> {
+    let north = textureSample(map, map_sampler, uv + vec2(0.0, settings.pixel_size)).x;
+    let south = textureSample(map, map_sampler, uv + vec2(0.0, -settings.pixel_size)).x;
+    let east = textureSample(map, map_sampler, uv + vec2(settings.pixel_size, 0.0)).x;
+    let west = textureSample(map, map_sampler, uv + vec2(-settings.pixel_size, 0.0)).x;
+
+    return vec2((east - west) / (2.0 * settings.pixel_size), (south - north) / (2.0 * settings.pixel_size));
+}
+
+fn outside_border(uv: vec2<f32>) -> bool {
+    let map_size = 1.0 / settings.pixel_size;
+    let coord = uv * map_size;
+
+    return coord.x >= map_size - f32(settings.erosion_radius)
+        || coord.y >= map_size - f32(settings.erosion_radius)
+        || coord.x < f32(settings.erosion_radius) || coord.y < f32(settings.erosion_radius);
+}
+
+// Piecewise approximation of a perceptual blue to red ramp.
+fn false_color(t: f32) -> vec3<f32> {
+    let x = clamp(t, 0.0, 1.0);
+    let r = smoothstep(0.35, 0.65, x) + smoothstep(0.9, 1.0, x) * -0.2;
+    let g = smoothstep(0.1, 0.4, x) - smoothstep(0.7, 1.0, x);
+    let b = 0.5 + smoothstep(0.0, 0.15, x) * 0.5 - smoothstep(0.25, 0.55, x);
+    return vec3(r, g, b);
+}
+
+// Blue for negative, red for positive values.
+fn diverging_color(t: f32) -> vec3<f32> {
+    let x = clamp(t, -1.0, 1.0);
+    return mix(vec3(1.0), select(vec3(0.8, 0.1, 0.1), vec3(0.1, 0.2, 0.8), x < 0.0), abs(x));
+}
+
+// Raw channel visualizations selected by `view_mode`, 0 is the regular shading.
+fn view_mode_color(world: vec2<f32>, uv: vec2<f32>) -> vec3<f32> {
+    let sample = textureSampleLevel(map, map_sampler, uv, 0.0);
+    let normal = height_normal(world);
+
+    var col = vec3(0.0);
+    switch settings.view_mode {
+        case 1u: {
+            col = false_color(sample.x);
+        }
+        case 2u: {
+            col = false_color(acos(clamp(normal.y, 0.0, 1.0)) / (0.5 * 3.14159265));
+        }
+        case 3u: {
+            let texel = settings.world_size * settings.pixel_size;
+            let laplacian = sample_height(world + vec2(texel, 0.0)) + sample_height(world - vec2(texel, 0.0))
+                + sample_height(world + vec2(0.0, texel)) + sample_height(world - vec2(0.0, texel))
+                - 4.0 * sample.x * settings.terrain_height;
+            col = diverging_color(laplacian / texel * 4.0);
+        }
+        case 4u: {
+            col = vec3(1.0 - sample.y);
+        }
+        case 5u: {
+            col = normal * 0.5 + 0.5;
+        }
+        case 6u: {
+            col = diverging_color(sample.w * 200.0);
+        }
+        default: {}
+    }
+
+    return col;
+}
+
+fn border_tint(col: vec3<f32>, world: vec2<f32>) -> vec3<f32> {
+    let stripe = step(0.5, fract((world.x + world.y) * 0.25));
+    return mix(col, vec3(1.0, 0.0, 1.0), 0.3 + 0.3 * stripe);
+}
+
+// Coverage of lines at integer values of `x`, `width` in pixels, anti-aliased over one pixel.
+fn line_coverage(x: vec2<f32>, width: f32) -> vec2<f32> {
+    let dist = abs(fract(x + 0.5) - 0.5) / max(fwidth(x), vec2(1e-5));
+    return 1.0 - clamp(dist - width * 0.5 + 0.5, vec2(0.0), vec2(1.0));
+}
+
+fn apply_overlays(col: vec3<f32>, world: vec3<f32>) -> vec3<f32> {
+    let contour = world.y / max(settings.contour_interval, 1e-3);
+    let index = u32(round(contour)) % max(settings.index_contour_every, 1u) == 0u;
+    let contour_width = select(1.0, 2.0, index) * settings.line_width;
+    let contour_line = line_coverage(vec2(contour), contour_width).x;
+    let grid_line = line_coverage(world.xz / max(settings.grid_spacing, 1e-3), settings.line_width);
+
+    var out = col;
+    if settings.grid != 0u {
+        out = mix(out, vec3(1.0), max(grid_line.x, grid_line.y) * 0.5);
+    }
+    if settings.contours != 0u {
+        out = mix(out, vec3(0.05), contour_line * select(0.6, 0.9, index));
+    }
+    if settings.show_erosion_mask != 0u {
+        let size = vec2<f32>(textureDimensions(erosion_mask));
+        let texel = clamp(vec2<i32>(world_to_uv(world.xz) * size), vec2(0), vec2<i32>(size) - 1);
+        out = mix(out, vec3(0.2, 0.5, 1.0), textureLoad(erosion_mask, texel, 0).x * 0.5);
+    }
+
+    return out;
+}
+
+// `sun_direction` is flipped for the palette shading, undo that and scale the normalized
+// height axis back to world units.
+fn sun_world_direction() -> vec3<f32> {
+    return normalize(settings.sun_direction * vec3(1.0, -1.0, -1.0) * vec3(settings.world_size, settings.terrain_height, settings.world_size));
+}
+
+fn sky_color(dir: vec3<f32>, sun_disc: bool) -> vec3<f32> {
+    let sun = sun_world_direction();
+    let sun_amount = max(dot(dir, sun), 0.0);
+
+    var col = mix(settings.sky_horizon, settings.sky_zenith, pow(clamp(dir.y, 0.0, 1.0), 0.5));
+    col = mix(col, settings.sky_horizon * 0.5, clamp(-dir.y * 4.0, 0.0, 1.0));
+    col += settings.sun_color * pow(sun_amount, 8.0) * 0.3;
+
+    if sun_disc {
+        let edge = cos(settings.sun_disc_size);
+        col += settings.sun_color * smoothstep(edge, mix(edge, 1.0, 0.2), sun_amount) * 4.0;
+    }
+
+    return col;
+}
+
+// Exponential height fog integrated along the view ray, colored by the sky behind it.
+fn apply_fog(col: vec3<f32>, world: vec3<f32>) -> vec3<f32> {
+    let ray = world - view.world_position;
+    let dist = length(ray);
+    let dir = ray / max(dist, 1e-4);
+
+    let falloff = settings.fog_height_falloff * ray.y;
+    var height_term = 1.0;
+    if abs(falloff) > 1e-4 {
+        height_term = (1.0 - exp(-falloff)) / falloff;
+    }
+
+    let optical_depth = settings.fog_density * exp(-settings.fog_height_falloff * view.world_position.y) * dist * height_term;
+    let fog = 1.0 - exp(-max(optical_depth, 0.0));
+
+    return mix(col, sky_color(dir, false), fog);
+}
+
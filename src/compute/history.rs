@@ -0,0 +1,276 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat, TextureUsages},
+    },
+};
+
+use super::{node::MountainErosionStatus, uniforms::{MountainHeightChanged, RegenerateMountain}, TEXTURE_SIZE};
+
+#[derive(Resource, Reflect, Clone)]
+#[reflect(Resource)]
+pub struct MountainHistorySettings {
+    // Snapshots keep height and erosion at full resolution, 128MB each at 4096.
+    pub max_snapshots: usize,
+}
+
+impl Default for MountainHistorySettings {
+    fn default() -> Self {
+        Self {
+            max_snapshots: 4,
+        }
+    }
+}
+
+// Sent by anything about to overwrite the height channel, FBM regeneration and the start
+// of an erosion run are picked up without it.
+#[derive(Event)]
+pub struct SnapshotHeightmap;
+
+#[derive(Event)]
+pub struct UndoHeightmap;
+
+#[derive(Event)]
+pub struct RedoHeightmap;
+
+#[derive(Clone)]
+pub enum HistoryOp {
+    Save(Handle<Image>),
+    Restore(Handle<Image>),
+}
+
+// Copies for the snapshot stages, `schedule_compute_stages` hands them to the node one per frame
+// before any other pass.
+#[derive(Resource, Default)]
+pub struct MountainHistoryOps(pub VecDeque<HistoryOp>);
+
+#[derive(Resource, Default)]
+pub struct MountainHistory {
+    undo: VecDeque<Handle<Image>>,
+    redo: Vec<Handle<Image>>,
+    free: Vec<Handle<Image>>,
+}
+
+fn create_snapshot_texture() -> Image {
+    let mut im = Image::new_fill(
+        Extent3d {
+            width: TEXTURE_SIZE,
+            height: TEXTURE_SIZE,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0; 8],
+        TextureFormat::Rg32Float,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+
+    im.texture_descriptor.usage = TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING;
+    im
+}
+
+impl MountainHistory {
+    fn len(&self) -> usize {
+        self.undo.len() + self.redo.len() + self.free.len()
+    }
+
+    // The oldest undo step or the redo step furthest from the current heightmap, whichever history
+    // is longer, so the step just saved is kept.
+    fn pop_oldest(&mut self) -> Option<Handle<Image>> {
+        if self.undo.len() >= self.redo.len() {
+            self.undo.pop_front()
+        } else {
+            Some(self.redo.remove(0))
+        }
+    }
+
+    // Reuses a free snapshot, then the oldest step once at the limit. `create` allocates a new one.
+    fn slot(&mut self, create: &mut impl FnMut() -> Handle<Image>, max_snapshots: usize) -> Handle<Image> {
+        if let Some(slot) = self.free.pop() {
+            return slot;
+        }
+
+        if self.len() >= max_snapshots.max(1) {
+            if let Some(oldest) = self.pop_oldest() {
+                return oldest;
+            }
+        }
+
+        create()
+    }
+
+    // Drops the handles over the limit, which frees their textures, unused snapshots first and then
+    // the oldest steps. Needed after undo or redo swap a snapshot out, or the limit is lowered.
+    fn trim(&mut self, max_snapshots: usize) {
+        while self.len() > max_snapshots.max(1) {
+            if self.free.pop().is_none() && self.pop_oldest().is_none() {
+                break;
+            }
+        }
+    }
+
+    fn snapshot(&mut self, ops: &mut VecDeque<HistoryOp>, create: &mut impl FnMut() -> Handle<Image>, max_snapshots: usize) {
+        self.free.append(&mut self.redo);
+
+        let slot = self.slot(create, max_snapshots);
+        ops.push_back(HistoryOp::Save(slot.clone()));
+        self.undo.push_back(slot);
+        self.trim(max_snapshots);
+    }
+
+    fn undo(&mut self, ops: &mut VecDeque<HistoryOp>, create: &mut impl FnMut() -> Handle<Image>, max_snapshots: usize) -> bool {
+        let Some(target) = self.undo.pop_back() else { return false };
+
+        let slot = self.slot(create, max_snapshots);
+        ops.push_back(HistoryOp::Save(slot.clone()));
+        ops.push_back(HistoryOp::Restore(target.clone()));
+        self.redo.push(slot);
+        self.free.push(target);
+        self.trim(max_snapshots);
+        true
+    }
+
+    fn redo(&mut self, ops: &mut VecDeque<HistoryOp>, create: &mut impl FnMut() -> Handle<Image>, max_snapshots: usize) -> bool {
+        let Some(target) = self.redo.pop() else { return false };
+
+        let slot = self.slot(create, max_snapshots);
+        ops.push_back(HistoryOp::Save(slot.clone()));
+        ops.push_back(HistoryOp::Restore(target.clone()));
+        self.undo.push_back(slot);
+        self.free.push(target);
+        self.trim(max_snapshots);
+        true
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn update_history(
+    settings: Res<MountainHistorySettings>,
+    mut history: ResMut<MountainHistory>,
+    mut ops: ResMut<MountainHistoryOps>,
+    mut images: ResMut<Assets<Image>>,
+    erosion_status: Res<MountainErosionStatus>,
+    mut was_eroding: Local<bool>,
+    mut regenerate_evr: EventReader<RegenerateMountain>,
+    mut snapshot_evr: EventReader<SnapshotHeightmap>,
+    mut undo_evr: EventReader<UndoHeightmap>,
+    mut redo_evr: EventReader<RedoHeightmap>,
+    mut height_evw: EventWriter<MountainHeightChanged>,
+) {
+    let eroding = *erosion_status == MountainErosionStatus::Update;
+    let started_erosion = eroding && !*was_eroding;
+    *was_eroding = eroding;
+
+    let max_snapshots = settings.max_snapshots;
+    let mut create = || images.add(create_snapshot_texture());
+
+    if regenerate_evr.read().count() + snapshot_evr.read().count() > 0 || started_erosion {
+        history.snapshot(&mut ops.0, &mut create, max_snapshots);
+    }
+
+    for _ev in undo_evr.read() {
+        if history.undo(&mut ops.0, &mut create, max_snapshots) {
+            height_evw.send(MountainHeightChanged);
+        }
+    }
+
+    for _ev in redo_evr.read() {
+        if history.redo(&mut ops.0, &mut create, max_snapshots) {
+            height_evw.send(MountainHeightChanged);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::utils::{HashSet, Uuid};
+
+    use super::*;
+
+    // Stands in for the snapshot textures, which are too large to allocate in tests.
+    fn counter() -> impl FnMut() -> Handle<Image> {
+        let mut next = 0;
+        move || {
+            next += 1;
+            Handle::Weak(AssetId::Uuid { uuid: Uuid::from_u128(next) })
+        }
+    }
+
+    fn restored(ops: &VecDeque<HistoryOp>) -> Option<&Handle<Image>> {
+        ops.iter().rev().find_map(|op| match op {
+            HistoryOp::Restore(handle) => Some(handle),
+            HistoryOp::Save(_) => None,
+        })
+    }
+
+    #[test]
+    fn undo_restores_the_last_snapshot_and_redo_returns() {
+        let mut history = MountainHistory::default();
+        let mut ops = VecDeque::new();
+        let mut create = counter();
+
+        history.snapshot(&mut ops, &mut create, 4);
+        let HistoryOp::Save(saved) = ops[0].clone() else { panic!("expected a save") };
+
+        assert!(history.undo(&mut ops, &mut create, 4));
+        assert_eq!(restored(&ops), Some(&saved));
+        let HistoryOp::Save(current) = ops[1].clone() else { panic!("expected a save") };
+
+        assert!(history.redo(&mut ops, &mut create, 4));
+        assert_eq!(restored(&ops), Some(&current));
+
+        assert!(!history.redo(&mut ops, &mut create, 4));
+    }
+
+    #[test]
+    fn slots_are_reused_once_at_the_limit() {
+        let mut history = MountainHistory::default();
+        let mut ops = VecDeque::new();
+        let mut create = counter();
+
+        for _ in 0..10 {
+            history.snapshot(&mut ops, &mut create, 3);
+            assert!(history.len() <= 3);
+        }
+        let saved = ops.iter().filter_map(|op| match op {
+            HistoryOp::Save(handle) => Some(handle.id()),
+            HistoryOp::Restore(_) => None,
+        }).collect::<HashSet<_>>();
+        assert_eq!(saved.len(), 3);
+
+        for _ in 0..10 {
+            history.undo(&mut ops, &mut create, 3);
+            assert!(history.len() <= 3);
+        }
+        for _ in 0..10 {
+            history.redo(&mut ops, &mut create, 3);
+            assert!(history.len() <= 3);
+        }
+    }
+
+    #[test]
+    fn lowering_the_limit_drops_the_furthest_redo_steps() {
+        let mut history = MountainHistory::default();
+        let mut ops = VecDeque::new();
+        let mut create = counter();
+
+        for _ in 0..4 {
+            history.snapshot(&mut ops, &mut create, 4);
+        }
+        for _ in 0..3 {
+            assert!(history.undo(&mut ops, &mut create, 4));
+        }
+        assert_eq!((history.undo.len(), history.redo.len()), (1, 3));
+        let nearest = history.redo[1].clone();
+
+        ops.clear();
+        assert!(history.redo(&mut ops, &mut create, 2));
+        let HistoryOp::Save(saved) = ops[0].clone() else { panic!("expected a save") };
+
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.undo.back(), Some(&saved));
+        assert_eq!(history.redo, vec![nearest]);
+    }
+}
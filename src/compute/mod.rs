@@ -1,58 +1,217 @@
+use std::sync::{mpsc::channel, Mutex};
+
 use bevy::{prelude::*, render::{
     extract_resource::ExtractResourcePlugin, render_graph::RenderGraph,
     Render, RenderApp, RenderSet,
 }};
-use node::{MountainComputeNode, MountainErosionStatus, MountainGenerateFBMStatus, MountainGenerateShadowStatus, MountainPrepareWriteStatus, MountainRenderLabel};
-use pipeline::MountainComputePipeline;
+use bevy_panorbit_camera::PanOrbitCameraSystemSet;
+use history::{
+    update_history, MountainHistory, MountainHistoryOps, MountainHistorySettings, RedoHeightmap, SnapshotHeightmap,
+    UndoHeightmap,
+};
+use node::{
+    erosion_bind_groups, erosion_dispatch, map_dispatch, pick_dispatch, ping_pong_bind_groups, sculpt_bind_groups,
+    snapshot_dispatch, snapshot_restore_bind_groups, snapshot_save_bind_groups, write_bind_groups,
+    MountainComputeNode, MountainComputeReady, MountainErosionStatus, MountainRenderLabel,
+};
+use pipeline::{
+    erosion_layouts, ping_pong_layouts, sculpt_layouts, snapshot_restore_layouts, snapshot_save_layouts, write_layouts,
+    MountainComputePipeline,
+};
+use sculpt::{
+    draw_sculpt_cursor, prepare_sculpt_uniforms, readback_sculpt_pick, receive_sculpt_pick, update_sculpt_stamp,
+    MountainSculptBuffers, MountainSculptCursor, MountainSculptPickReceiver, MountainSculptPickSender,
+    MountainSculptSettings, MountainSculptStamp, MountainSculptUniforms,
+};
+use stage::{
+    schedule_compute_stages, MountainComputeQueue, MountainComputeRuns, MountainComputeStage, MountainComputeStageAppExt,
+    MountainComputeStages, AO_STAGE, EROSION_STAGE, FBM_STAGE, PICK_STAGE, PREPARE_WRITE_STAGE, SCULPT_STAGE, SHADOW_STAGE,
+    SNAPSHOT_RESTORE_STAGE, SNAPSHOT_SAVE_STAGE,
+};
 use uniforms::{
-    prepare_storage, prepare_uniforms, setup_storage, setup_textures, update_erosion_status, update_generate_fbm_status, update_generate_shadow_status, update_prepare_write_status, MountainBrushIndices, MountainBrushStorage, MountainBrushWeights, MountainComputeSettings, MountainComputeTextures, MountainComputeUniforms, MountainErosionTrigger, PrepareWriteCompute, RegenerateMountain, RegenerateShadows
+    invalidate_derived_maps, prepare_storage, prepare_uniforms, setup_storage, setup_textures, adapt_erosion_batch, clamp_erosion_batch, enable_turbo_erosion, prepare_erosion_batches, reset_erosion_progress, update_erosion_iteration, update_erosion_status, ErosionFinished, MountainBakeSettings, MountainBrushIndices, MountainBrushStorage, MountainBrushWeights, MountainComputeSettings, MountainComputeTextures, MountainComputeUniforms, MountainErosionBatchSettings, MountainErosionBatchUniforms, MountainErosionBatches, MountainErosionProgress, MountainErosionTrigger, MountainHeightChanged, PrepareWriteCompute, RegenerateAmbientOcclusion, RegenerateMountain, RegenerateShadows
 };
 
 pub const TEXTURE_SIZE: u32 = 4096;
+// The erosion mask is painted at a lower resolution than the heightmap.
+pub const MASK_SIZE: u32 = TEXTURE_SIZE / 4;
 pub const WORKGROUP_SIZE: u32 = 8;
 pub const NUM_EROSIONS: u32 = 64;
+// NOTE: Matches the erosion shader's workgroup size.
+pub const DROPLETS_PER_WORKGROUP: u32 = 64;
+// Default batch size, and the fixed one erosion ran with before it was configurable.
+pub const DROPLETS_PER_DISPATCH: u32 = NUM_EROSIONS * DROPLETS_PER_WORKGROUP;
 
+pub mod history;
 pub mod node;
 pub mod pipeline;
+pub mod sculpt;
+pub mod stage;
 pub mod uniforms;
 
 pub struct MountainComputePlugin;
 
 impl Plugin for MountainComputePlugin {
     fn build(&self, app: &mut App) {
+        let ready = MountainComputeReady::default();
+        let (pick_sender, pick_receiver) = channel();
+
+        // The heightmap and everything baked from it are generated on startup, unless it's imported.
+        // A failed import requests the heightmap itself.
+        let mut queue = MountainComputeQueue::default();
+        if crate::launch_arg("--dem").is_none() {
+            queue.request(FBM_STAGE);
+        }
+        queue.request(SHADOW_STAGE);
+        queue.request(AO_STAGE);
+
         app
+            .insert_resource(ready.clone())
             .init_resource::<MountainComputeSettings>()
+            .init_resource::<MountainBakeSettings>()
+            .init_resource::<MountainSculptSettings>()
+            .init_resource::<MountainHistorySettings>()
+            .init_resource::<MountainHistory>()
+            .init_resource::<MountainHistoryOps>()
+            .init_resource::<MountainSculptStamp>()
+            .init_resource::<MountainSculptCursor>()
+            .insert_resource(MountainSculptPickReceiver(Mutex::new(pick_receiver)))
             .init_resource::<MountainBrushWeights>()
             .init_resource::<MountainBrushIndices>()
-            .init_resource::<MountainGenerateFBMStatus>()
-            .init_resource::<MountainGenerateShadowStatus>()
+            .insert_resource(queue)
+            .init_resource::<MountainComputeRuns>()
             .init_resource::<MountainErosionStatus>()
-            .init_resource::<MountainPrepareWriteStatus>()
+            .init_resource::<MountainErosionProgress>()
+            .init_resource::<MountainErosionBatchSettings>()
+            .init_resource::<MountainErosionBatches>()
             .add_event::<RegenerateMountain>()
             .add_event::<RegenerateShadows>()
+            .add_event::<RegenerateAmbientOcclusion>()
+            .add_event::<MountainHeightChanged>()
+            .add_event::<SnapshotHeightmap>()
+            .add_event::<UndoHeightmap>()
+            .add_event::<RedoHeightmap>()
             .add_event::<MountainErosionTrigger>()
+            .add_event::<ErosionFinished>()
             .add_event::<PrepareWriteCompute>()
-            .add_systems(Startup, (setup_textures, setup_storage))
-            .add_systems(Update, (update_generate_fbm_status, update_erosion_status, update_generate_shadow_status, update_prepare_write_status))
+            .add_systems(Startup, (setup_textures, setup_storage, enable_turbo_erosion))
+            .add_systems(Update, (reset_erosion_progress, update_erosion_status))
+            .add_systems(Update, invalidate_derived_maps.after(update_erosion_status))
+            .add_systems(Update, (update_sculpt_stamp.before(PanOrbitCameraSystemSet), receive_sculpt_pick, draw_sculpt_cursor))
+            .add_systems(Update, (clamp_erosion_batch, adapt_erosion_batch).chain())
+            .add_systems(PostUpdate, (update_history, update_erosion_iteration, schedule_compute_stages).chain())
             .add_plugins((
                 ExtractResourcePlugin::<MountainComputeSettings>::default(),
                 ExtractResourcePlugin::<MountainBrushWeights>::default(),
                 ExtractResourcePlugin::<MountainBrushIndices>::default(),
                 ExtractResourcePlugin::<MountainComputeTextures>::default(),
-                ExtractResourcePlugin::<MountainGenerateFBMStatus>::default(),
-                ExtractResourcePlugin::<MountainGenerateShadowStatus>::default(),
-                ExtractResourcePlugin::<MountainPrepareWriteStatus>::default(),
-                ExtractResourcePlugin::<MountainErosionStatus>::default(),
+                ExtractResourcePlugin::<MountainComputeRuns>::default(),
+                ExtractResourcePlugin::<MountainErosionBatches>::default(),
+                ExtractResourcePlugin::<MountainSculptStamp>::default(),
             ));
 
+        app
+            .add_compute_stage(MountainComputeStage {
+                label: SNAPSHOT_SAVE_STAGE,
+                shader: "shaders/snapshot.wgsl",
+                entry_point: "save",
+                layouts: snapshot_save_layouts,
+                bind_groups: snapshot_save_bind_groups,
+                dispatch: snapshot_dispatch,
+                ping_pong: false,
+            })
+            .add_compute_stage(MountainComputeStage {
+                label: SNAPSHOT_RESTORE_STAGE,
+                shader: "shaders/snapshot.wgsl",
+                entry_point: "restore",
+                layouts: snapshot_restore_layouts,
+                bind_groups: snapshot_restore_bind_groups,
+                dispatch: snapshot_dispatch,
+                ping_pong: false,
+            })
+            .add_compute_stage(MountainComputeStage {
+                label: FBM_STAGE,
+                shader: "shaders/height.wgsl",
+                entry_point: "height",
+                layouts: ping_pong_layouts,
+                bind_groups: ping_pong_bind_groups,
+                dispatch: map_dispatch,
+                ping_pong: true,
+            })
+            .add_compute_stage(MountainComputeStage {
+                label: SHADOW_STAGE,
+                shader: "shaders/height.wgsl",
+                entry_point: "shadow",
+                layouts: ping_pong_layouts,
+                bind_groups: ping_pong_bind_groups,
+                dispatch: map_dispatch,
+                ping_pong: true,
+            })
+            .add_compute_stage(MountainComputeStage {
+                label: AO_STAGE,
+                shader: "shaders/height.wgsl",
+                entry_point: "ambient_occlusion",
+                layouts: ping_pong_layouts,
+                bind_groups: ping_pong_bind_groups,
+                dispatch: map_dispatch,
+                ping_pong: true,
+            })
+            .add_compute_stage(MountainComputeStage {
+                label: EROSION_STAGE,
+                shader: "shaders/erosion.wgsl",
+                entry_point: "erode",
+                layouts: erosion_layouts,
+                bind_groups: erosion_bind_groups,
+                dispatch: erosion_dispatch,
+                ping_pong: false,
+            })
+            .add_compute_stage(MountainComputeStage {
+                label: PICK_STAGE,
+                shader: "shaders/sculpt.wgsl",
+                entry_point: "pick",
+                layouts: sculpt_layouts,
+                bind_groups: sculpt_bind_groups,
+                dispatch: pick_dispatch,
+                ping_pong: false,
+            })
+            .add_compute_stage(MountainComputeStage {
+                label: SCULPT_STAGE,
+                shader: "shaders/sculpt.wgsl",
+                entry_point: "apply",
+                layouts: sculpt_layouts,
+                bind_groups: sculpt_bind_groups,
+                dispatch: map_dispatch,
+                ping_pong: true,
+            })
+            .add_compute_stage(MountainComputeStage {
+                label: PREPARE_WRITE_STAGE,
+                shader: "shaders/write.wgsl",
+                entry_point: "prepare",
+                layouts: write_layouts,
+                bind_groups: write_bind_groups,
+                dispatch: map_dispatch,
+                ping_pong: false,
+            })
+            .queue_compute_stage_on::<RegenerateMountain>(FBM_STAGE)
+            .queue_compute_stage_on::<RegenerateMountain>(SHADOW_STAGE)
+            .queue_compute_stage_on::<RegenerateMountain>(AO_STAGE)
+            .queue_compute_stage_on::<RegenerateShadows>(SHADOW_STAGE)
+            .queue_compute_stage_on::<RegenerateAmbientOcclusion>(AO_STAGE)
+            .queue_compute_stage_on::<PrepareWriteCompute>(PREPARE_WRITE_STAGE);
+
         let render_app = app.sub_app_mut(RenderApp);
         render_app
+            .insert_resource(ready)
             .init_resource::<MountainComputeUniforms>()
+            .init_resource::<MountainErosionBatchUniforms>()
             .init_resource::<MountainBrushStorage>()
-            .add_systems(Render, (prepare_uniforms, prepare_storage).in_set(RenderSet::Prepare));
+            .init_resource::<MountainSculptUniforms>()
+            .insert_resource(MountainSculptPickSender(Mutex::new(pick_sender)))
+            .add_systems(Render, (prepare_uniforms, prepare_storage, prepare_erosion_batches, prepare_sculpt_uniforms).in_set(RenderSet::Prepare))
+            .add_systems(Render, readback_sculpt_pick.in_set(RenderSet::Cleanup));
 
         let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
-        render_graph.add_node(MountainRenderLabel, MountainComputeNode::default());
+        render_graph.add_node(MountainRenderLabel, MountainComputeNode);
         render_graph.add_node_edges((
             MountainRenderLabel,
             bevy::render::graph::CameraDriverLabel,
@@ -60,7 +219,12 @@ impl Plugin for MountainComputePlugin {
     }
 
     fn finish(&self, app: &mut App) {
+        let stages = app.world.resource::<MountainComputeStages>().clone();
+
         let render_app = app.sub_app_mut(RenderApp);
-        render_app.init_resource::<MountainComputePipeline>();
+        render_app
+            .insert_resource(stages)
+            .init_resource::<MountainComputePipeline>()
+            .init_resource::<MountainSculptBuffers>();
     }
 }
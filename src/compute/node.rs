@@ -1,97 +1,54 @@
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+
 use bevy::{
     prelude::*,
     render::{
-        extract_resource::ExtractResource,
         render_asset::RenderAssets,
         render_graph::{self, RenderLabel},
-        render_resource::{BindGroupEntry, BindingResource, ComputePassDescriptor, PipelineCache},
+        render_resource::{BindGroup, BindGroupEntry, BindingResource, ComputePassDescriptor, PipelineCache},
+        renderer::RenderDevice,
     },
 };
 
-use super::{pipeline::MountainComputePipeline, uniforms::{MountainBrushStorage, MountainComputeTextures, MountainComputeUniforms}, NUM_EROSIONS, TEXTURE_SIZE, WORKGROUP_SIZE};
-
-#[derive(Resource, ExtractResource, Default, Clone, Copy)]
-pub enum MountainGenerateFBMStatus {
-    #[default]
-    Update,
-    Wait
-}
-
-#[derive(Resource, ExtractResource, Default, Clone, Copy)]
-pub enum MountainGenerateShadowStatus {
-    #[default]
-    Update,
-    Wait,
-}
+use super::{
+    history::HistoryOp,
+    pipeline::MountainComputePipeline,
+    sculpt::{MountainSculptBuffers, MountainSculptUniforms},
+    stage::{MountainComputeRuns, StageContext, StageDispatch},
+    uniforms::{MountainBrushStorage, MountainComputeTextures, MountainComputeUniforms, MountainErosionBatchUniforms, MountainErosionBatches},
+    TEXTURE_SIZE, WORKGROUP_SIZE,
+};
 
-#[derive(Resource, ExtractResource, Default, Clone, Copy, PartialEq, Eq)]
+#[derive(Resource, Default, Clone, Copy, PartialEq, Eq)]
 pub enum MountainErosionStatus {
     Update,
     #[default]
     Wait
 }
 
-#[derive(Resource, ExtractResource, Default, Clone, Copy)]
-pub enum MountainPrepareWriteStatus {
-    Update,
-    #[default]
-    Wait,
+#[derive(Resource, Default, Clone)]
+pub struct MountainComputeReady(Arc<AtomicBool>);
+
+impl MountainComputeReady {
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
 pub struct MountainRenderLabel;
 
-#[derive(Default)]
-pub struct MountainComputeNode {
-    generate_shadow: bool,
-    generate_fbm: bool,
-    enable_erosion: bool,
-    prepare_write: bool,
-}
+pub struct MountainComputeNode;
 
 impl render_graph::Node for MountainComputeNode {
     fn update(&mut self, world: &mut World) {
-        let erosion_status = world.resource::<MountainErosionStatus>();
-        self.enable_erosion = *erosion_status == MountainErosionStatus::Update;
-
-        let mut fbm_status = world.resource_mut::<MountainGenerateFBMStatus>();
-
-        if let MountainGenerateFBMStatus::Update = *fbm_status {
-            if self.generate_fbm {
-                *fbm_status = MountainGenerateFBMStatus::Wait;
-                self.generate_fbm = false;
-            } else {
-                self.generate_fbm = true;
-            }
-        } else {
-            self.generate_fbm = false;
-        }
-
-        let mut shadow_status = world.resource_mut::<MountainGenerateShadowStatus>();
-
-        if let MountainGenerateShadowStatus::Update = *shadow_status {
-            if self.generate_shadow {
-                *shadow_status = MountainGenerateShadowStatus::Wait;
-                self.generate_shadow = false;
-            } else {
-                self.generate_shadow = true
-            }
-        } else {
-            self.generate_shadow = false;
-        }
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let compute_pipelines = world.resource::<MountainComputePipeline>();
 
-        let mut prepare_write_status = world.resource_mut::<MountainPrepareWriteStatus>();
+        let ready = compute_pipelines.stages.iter()
+            .all(|prepared| pipeline_cache.get_compute_pipeline(prepared.pipeline).is_some());
 
-        if let MountainPrepareWriteStatus::Update = *prepare_write_status {
-            if self.prepare_write {
-                *prepare_write_status = MountainPrepareWriteStatus::Wait;
-                self.prepare_write = false;
-            } else {
-                self.prepare_write = true;
-            }
-        } else {
-            self.prepare_write = false;
-        }
+        world.resource::<MountainComputeReady>().0.store(ready, Ordering::Relaxed);
     }
 
     fn run<'w>(
@@ -102,107 +59,270 @@ impl render_graph::Node for MountainComputeNode {
     ) -> Result<(), render_graph::NodeRunError> {
         let pipeline_cache = world.resource::<PipelineCache>();
         let compute_pipelines = world.resource::<MountainComputePipeline>();
+        let render_device = world.resource::<RenderDevice>();
 
         let gpu_images = world.resource::<RenderAssets<Image>>();
         let mountain_textures = world.resource::<MountainComputeTextures>();
+        let runs = world.resource::<MountainComputeRuns>();
+
+        // Follows the swaps the main world made when it scheduled the ping pong stages.
+        let mut front = runs.front;
+
+        for prepared in compute_pipelines.stages.iter() {
+            let stage = &prepared.stage;
+            let context = StageContext {
+                world,
+                render_device,
+                layouts: &prepared.layouts,
+                map: gpu_images.get(&mountain_textures.maps[front]).unwrap(),
+                map_back: gpu_images.get(&mountain_textures.maps[1 - front]).unwrap(),
+                queued: runs.stages.contains(stage.label),
+            };
 
-        let uniforms = world.resource::<MountainComputeUniforms>();
-        let brush_storage = world.resource::<MountainBrushStorage>();
-        
-        let map = &gpu_images.get(&mountain_textures.map).unwrap();
-
-        let bind_group = render_context
-            .render_device()
-            .create_bind_group(
-                Some("mountain_compute_pass_bind_group"),
-                &compute_pipelines.layout,
-                &[
-                    BindGroupEntry {
-                        binding: 0,
-                        resource: uniforms.buf.binding().unwrap(),
-                    },
-                    BindGroupEntry {
-                        binding: 1,
-                        resource: BindingResource::TextureView(&map.texture_view),
-                    },
-                    BindGroupEntry {
-                        binding: 2,
-                        resource: brush_storage.indices.binding().unwrap(),
-                    },
-                    BindGroupEntry {
-                        binding: 3,
-                        resource: brush_storage.weights.binding().unwrap(),
-                    },
-                ]
-            );
-
-        let write_bind_group = render_context
-            .render_device()
-            .create_bind_group(
-                Some("mountain_compute_prepare_write_bind_group"),
-                &compute_pipelines.write_layout,
-                &[
-                    BindGroupEntry {
-                        binding: 0,
-                        resource: BindingResource::TextureView(&map.texture_view),
-                    }
-                ]
-            );
+            let dispatches = (stage.dispatch)(&context);
+            if dispatches.is_empty() {
+                continue;
+            }
 
-        let encoder = render_context.command_encoder();
+            let Some(bind_groups) = (stage.bind_groups)(&context) else { continue };
+            let Some(pipeline) = pipeline_cache.get_compute_pipeline(prepared.pipeline) else {
+                return Ok(());
+            };
 
-        if self.generate_fbm {
-            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            let encoder = render_context.command_encoder();
 
-            pass.set_bind_group(0, &bind_group, &[]);
+            {
+                let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                    label: Some(stage.label),
+                    ..default()
+                });
 
-            let Some(pipeline) = pipeline_cache.get_compute_pipeline(compute_pipelines.fbm_pipeline) else {
-                return Ok(());
-            };
+                pass.set_pipeline(pipeline);
+
+                // Each dispatch sees what the previous one left behind, like consecutive erosion batches.
+                for dispatch in dispatches.iter() {
+                    for (i, bind_group) in bind_groups.iter().enumerate() {
+                        pass.set_bind_group(i as u32, bind_group, dispatch.offsets.get(i).map_or(&[], |offsets| offsets));
+                    }
+                    pass.dispatch_workgroups(dispatch.workgroups.x, dispatch.workgroups.y, dispatch.workgroups.z);
+                }
+            }
 
-            pass.set_pipeline(pipeline);
-            pass.dispatch_workgroups(TEXTURE_SIZE / WORKGROUP_SIZE, TEXTURE_SIZE / WORKGROUP_SIZE, 1);
+            // The next stage reads this one's result.
+            if stage.ping_pong {
+                front = 1 - front;
+            }
         }
-        
-        if self.generate_fbm || self.generate_shadow {
-            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
 
-            pass.set_bind_group(0, &bind_group, &[]);
+        Ok(())
+    }
+}
 
-            let Some(pipeline) = pipeline_cache.get_compute_pipeline(compute_pipelines.shadow_pipeline) else {
-                return Ok(());
-            };
+fn map_workgroups() -> UVec3 {
+    UVec3::new(TEXTURE_SIZE / WORKGROUP_SIZE, TEXTURE_SIZE / WORKGROUP_SIZE, 1)
+}
 
-            pass.set_pipeline(pipeline);
-            pass.dispatch_workgroups(TEXTURE_SIZE / WORKGROUP_SIZE, TEXTURE_SIZE / WORKGROUP_SIZE, 1);
-        }
+// One invocation per heightmap texel when queued.
+pub fn map_dispatch(context: &StageContext) -> Vec<StageDispatch> {
+    if !context.queued {
+        return Vec::new();
+    }
 
-        if self.enable_erosion {
-            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+    vec![StageDispatch::new(map_workgroups())]
+}
 
-            pass.set_bind_group(0, &bind_group, &[]);
+pub fn ping_pong_bind_groups(context: &StageContext) -> Option<Vec<BindGroup>> {
+    let uniforms = context.world.resource::<MountainComputeUniforms>();
+
+    Some(vec![context.render_device.create_bind_group(
+        Some("mountain_compute_ping_pong_bind_group"),
+        &context.layouts[0],
+        &[
+            BindGroupEntry {
+                binding: 0,
+                resource: uniforms.buf.binding().unwrap(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(&context.map.texture_view),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: BindingResource::TextureView(&context.map_back.texture_view),
+            },
+        ]
+    )])
+}
 
-            let Some(pipeline) = pipeline_cache.get_compute_pipeline(compute_pipelines.erosion_pipeline) else {
-                return Ok(());
-            };
+pub fn erosion_bind_groups(context: &StageContext) -> Option<Vec<BindGroup>> {
+    let gpu_images = context.world.resource::<RenderAssets<Image>>();
+    let mountain_textures = context.world.resource::<MountainComputeTextures>();
+    let uniforms = context.world.resource::<MountainComputeUniforms>();
+    let brush_storage = context.world.resource::<MountainBrushStorage>();
+    let erosion_batch_uniforms = context.world.resource::<MountainErosionBatchUniforms>();
+
+    let erosion_mask = gpu_images.get(&mountain_textures.erosion_mask)?;
+
+    let bind_group = context.render_device.create_bind_group(
+        Some("mountain_compute_pass_bind_group"),
+        &context.layouts[0],
+        &[
+            BindGroupEntry {
+                binding: 0,
+                resource: uniforms.buf.binding().unwrap(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(&context.map.texture_view),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: brush_storage.indices.binding().unwrap(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: brush_storage.weights.binding().unwrap(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: BindingResource::TextureView(&erosion_mask.texture_view),
+            },
+        ]
+    );
+
+    let batch_bind_group = context.render_device.create_bind_group(
+        Some("mountain_compute_erosion_batch_bind_group"),
+        &context.layouts[1],
+        &[
+            BindGroupEntry {
+                binding: 0,
+                resource: erosion_batch_uniforms.buf.binding()?,
+            },
+        ]
+    );
+
+    Some(vec![bind_group, batch_bind_group])
+}
 
-            pass.set_pipeline(pipeline);
-            pass.dispatch_workgroups(NUM_EROSIONS, 1, 1);
-        }
+// One dispatch per entry of `MountainErosionBatches`.
+pub fn erosion_dispatch(context: &StageContext) -> Vec<StageDispatch> {
+    if !context.queued {
+        return Vec::new();
+    }
 
-        if self.prepare_write {
-            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+    let erosion_batches = context.world.resource::<MountainErosionBatches>();
+    let erosion_batch_uniforms = context.world.resource::<MountainErosionBatchUniforms>();
 
-            pass.set_bind_group(0, &write_bind_group, &[]);
+    erosion_batches.0.iter().zip(erosion_batch_uniforms.offsets.iter()).map(|(batch, offset)| StageDispatch {
+        workgroups: UVec3::new(batch.workgroups(), 1, 1),
+        offsets: vec![Vec::new(), vec![*offset]],
+    }).collect()
+}
 
-            let Some(pipeline) = pipeline_cache.get_compute_pipeline(compute_pipelines.write_pipeline) else {
-                return Ok(());
-            };
+pub fn write_bind_groups(context: &StageContext) -> Option<Vec<BindGroup>> {
+    let gpu_images = context.world.resource::<RenderAssets<Image>>();
+    let mountain_textures = context.world.resource::<MountainComputeTextures>();
+
+    let export = gpu_images.get(&mountain_textures.export)?;
+
+    Some(vec![context.render_device.create_bind_group(
+        Some("mountain_compute_prepare_write_bind_group"),
+        &context.layouts[0],
+        &[
+            BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(&context.map.texture_view),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(&export.texture_view),
+            },
+        ]
+    )])
+}
 
-            pass.set_pipeline(pipeline);
-            pass.dispatch_workgroups(TEXTURE_SIZE / WORKGROUP_SIZE, TEXTURE_SIZE / WORKGROUP_SIZE, 1);
-        }
+pub fn sculpt_bind_groups(context: &StageContext) -> Option<Vec<BindGroup>> {
+    let gpu_images = context.world.resource::<RenderAssets<Image>>();
+    let mountain_textures = context.world.resource::<MountainComputeTextures>();
+    let sculpt_uniforms = context.world.resource::<MountainSculptUniforms>();
+    let sculpt_buffers = context.world.get_resource::<MountainSculptBuffers>()?;
+
+    let erosion_mask = gpu_images.get(&mountain_textures.erosion_mask)?;
+
+    Some(vec![context.render_device.create_bind_group(
+        Some("mountain_compute_sculpt_bind_group"),
+        &context.layouts[0],
+        &[
+            BindGroupEntry {
+                binding: 0,
+                resource: sculpt_uniforms.buf.binding().unwrap(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(&context.map.texture_view),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: sculpt_buffers.pick.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: BindingResource::TextureView(&erosion_mask.texture_view),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: BindingResource::TextureView(&context.map_back.texture_view),
+            },
+        ]
+    )])
+}
 
-        Ok(())
+pub fn pick_dispatch(context: &StageContext) -> Vec<StageDispatch> {
+    if !context.queued {
+        return Vec::new();
+    }
+
+    vec![StageDispatch::new(UVec3::ONE)]
+}
+
+fn snapshot_bind_groups(context: &StageContext, save: bool) -> Option<Vec<BindGroup>> {
+    let gpu_images = context.world.resource::<RenderAssets<Image>>();
+    let (handle, binding) = match context.world.resource::<MountainComputeRuns>().history.as_ref()? {
+        HistoryOp::Save(handle) if save => (handle, 1),
+        HistoryOp::Restore(handle) if !save => (handle, 2),
+        _ => return None,
+    };
+    let snapshot = gpu_images.get(handle)?;
+
+    Some(vec![context.render_device.create_bind_group(
+        Some("mountain_compute_snapshot_bind_group"),
+        &context.layouts[0],
+        &[
+            BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(&context.map.texture_view),
+            },
+            BindGroupEntry {
+                binding,
+                resource: BindingResource::TextureView(&snapshot.texture_view),
+            },
+        ]
+    )])
+}
+
+pub fn snapshot_save_bind_groups(context: &StageContext) -> Option<Vec<BindGroup>> {
+    snapshot_bind_groups(context, true)
+}
+
+pub fn snapshot_restore_bind_groups(context: &StageContext) -> Option<Vec<BindGroup>> {
+    snapshot_bind_groups(context, false)
+}
+
+// Driven by `MountainComputeRuns::history` rather than the queue, the bind groups pick the op.
+pub fn snapshot_dispatch(context: &StageContext) -> Vec<StageDispatch> {
+    if context.world.resource::<MountainComputeRuns>().history.is_none() {
+        return Vec::new();
     }
+
+    vec![StageDispatch::new(map_workgroups())]
 }
@@ -2,140 +2,321 @@ use bevy::{prelude::*, render::{
     render_resource::{
         BindGroupLayout, BindGroupLayoutEntry, BindingType, BufferBindingType,
         CachedComputePipelineId, ComputePipelineDescriptor, PipelineCache, ShaderStages,
-        ShaderType as _, StorageTextureAccess, TextureFormat, TextureViewDimension,
+        ShaderType as _, StorageTextureAccess, TextureFormat, TextureSampleType, TextureViewDimension,
     },
     renderer::RenderDevice,
 }};
 
-use super::uniforms::{MountainBrushIndices, MountainBrushWeights, MountainComputeSettings};
+use super::{sculpt::MountainSculptStamp, stage::{MountainComputeStage, MountainComputeStages}, uniforms::{ErosionBatch, MountainBrushIndices, MountainBrushWeights, MountainComputeUniform}};
+
+// A registered stage with the layouts it created and its queued pipeline.
+pub struct PreparedStage {
+    pub stage: MountainComputeStage,
+    pub layouts: Vec<BindGroupLayout>,
+    pub pipeline: CachedComputePipelineId,
+}
 
 #[derive(Resource)]
 pub struct MountainComputePipeline {
-    pub layout: BindGroupLayout,
-    pub write_layout: BindGroupLayout,
-
-    pub fbm_pipeline: CachedComputePipelineId,
-    pub shadow_pipeline: CachedComputePipelineId,
-    pub erosion_pipeline: CachedComputePipelineId,
-    pub write_pipeline: CachedComputePipelineId,
+    // In the order of `MountainComputeStages`.
+    pub stages: Vec<PreparedStage>,
 }
 
 impl FromWorld for MountainComputePipeline {
     fn from_world(world: &mut World) -> Self {
         let render_device = world.resource::<RenderDevice>();
-
-        let layout = render_device.create_bind_group_layout(
-            None,
-            &[
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: Some(MountainComputeSettings::min_size()),
-                    },
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::StorageTexture {
-                        access: StorageTextureAccess::ReadWrite,
-                        format: TextureFormat::Rgba32Float,
-                        view_dimension: TextureViewDimension::D2,
-                    },
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: Some(MountainBrushIndices::min_size()),
-                    },
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 3,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: Some(MountainBrushWeights::min_size()),
-                    },
-                    count: None,
-                },
-            ]
-        );
-
-        let write_layout = render_device.create_bind_group_layout(
-            None,
-            &[
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::StorageTexture {
-                        access: StorageTextureAccess::ReadWrite,
-                        format: TextureFormat::Rgba32Float,
-                        view_dimension: TextureViewDimension::D2,
-                    },
-                    count: None,
-                }
-            ]
-        );
-
         let asset_server = world.resource::<AssetServer>();
-        let height_shader = asset_server.load("shaders/height.wgsl");
-        let erosion_shader = asset_server.load("shaders/erosion.wgsl");
-        let write_shader = asset_server.load("shaders/write.wgsl");
+        let stages = world.resource::<MountainComputeStages>().0.iter()
+            .map(|stage| (stage.clone(), (stage.layouts)(render_device), asset_server.load(stage.shader)))
+            .collect::<Vec<(MountainComputeStage, Vec<BindGroupLayout>, Handle<Shader>)>>();
 
         let pipeline_cache = world.resource::<PipelineCache>();
 
-        let fbm_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: None,
-            layout: vec![layout.clone()],
-            push_constant_ranges: Vec::new(),
-            shader: height_shader.clone(),
-            shader_defs: vec![],
-            entry_point: "height".into(),
-        });
-
-        let shadow_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: None,
-            layout: vec![layout.clone()],
-            push_constant_ranges: Vec::new(),
-            shader: height_shader,
-            shader_defs: vec![],
-            entry_point: "shadow".into(),
-        });
-        
-        let erosion_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: None,
-            layout: vec![layout.clone()],
-            push_constant_ranges: Vec::new(),
-            shader: erosion_shader,
-            shader_defs: vec![],
-            entry_point: "erode".into(),
-        });
-
-        let write_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: None,
-            layout: vec![write_layout.clone()],
-            push_constant_ranges: Vec::new(),
-            shader: write_shader,
-            shader_defs: vec![],
-            entry_point: "prepare".into(),
-        });
-
-        MountainComputePipeline {
-            layout,
-            write_layout,
-            fbm_pipeline,
-            shadow_pipeline,
-            erosion_pipeline,
-            write_pipeline,
-        }
+        let stages = stages.into_iter().map(|(stage, layouts, shader)| {
+            let pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some(stage.label.into()),
+                layout: layouts.clone(),
+                push_constant_ranges: Vec::new(),
+                shader,
+                shader_defs: vec![],
+                entry_point: stage.entry_point.into(),
+            });
+
+            PreparedStage { stage, layouts, pipeline }
+        }).collect();
+
+        MountainComputePipeline { stages }
     }
 }
+
+// Settings, map, erosion brush and erosion mask, with the map read and written in place for
+// scattered updates that can't be double buffered. Group 1 is bound at each batch's offset.
+pub fn erosion_layouts(render_device: &RenderDevice) -> Vec<BindGroupLayout> {
+    let layout = render_device.create_bind_group_layout(
+        None,
+        &[
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(MountainComputeUniform::min_size()),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::StorageTexture {
+                    access: StorageTextureAccess::ReadWrite,
+                    format: TextureFormat::Rgba32Float,
+                    view_dimension: TextureViewDimension::D2,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(MountainBrushIndices::min_size()),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 3,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(MountainBrushWeights::min_size()),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 4,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::StorageTexture {
+                    access: StorageTextureAccess::ReadWrite,
+                    format: TextureFormat::R32Float,
+                    view_dimension: TextureViewDimension::D2,
+                },
+                count: None,
+            },
+        ]
+    );
+
+    let erosion_batch_layout = render_device.create_bind_group_layout(
+        None,
+        &[
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: Some(ErosionBatch::min_size()),
+                },
+                count: None,
+            },
+        ]
+    );
+
+    vec![layout, erosion_batch_layout]
+}
+
+// Settings, `map` as a sampled source and `map_back` as a write only destination.
+pub fn ping_pong_layouts(render_device: &RenderDevice) -> Vec<BindGroupLayout> {
+    let ping_pong_layout = render_device.create_bind_group_layout(
+        None,
+        &[
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(MountainComputeUniform::min_size()),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: false },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::StorageTexture {
+                    access: StorageTextureAccess::WriteOnly,
+                    format: TextureFormat::Rgba32Float,
+                    view_dimension: TextureViewDimension::D2,
+                },
+                count: None,
+            },
+        ]
+    );
+
+    vec![ping_pong_layout]
+}
+
+// Map and export texture.
+pub fn write_layouts(render_device: &RenderDevice) -> Vec<BindGroupLayout> {
+    let write_layout = render_device.create_bind_group_layout(
+        None,
+        &[
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: false },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::StorageTexture {
+                    access: StorageTextureAccess::WriteOnly,
+                    format: TextureFormat::Rgba32Float,
+                    view_dimension: TextureViewDimension::D2,
+                },
+                count: None,
+            },
+        ]
+    );
+
+    vec![write_layout]
+}
+
+// Sculpt stamp, `map` as a sampled source, pick result, erosion mask and `map_back` as a write
+// only destination.
+pub fn sculpt_layouts(render_device: &RenderDevice) -> Vec<BindGroupLayout> {
+    let sculpt_layout = render_device.create_bind_group_layout(
+        None,
+        &[
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(MountainSculptStamp::min_size()),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: false },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(Vec4::min_size()),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 3,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::StorageTexture {
+                    access: StorageTextureAccess::ReadWrite,
+                    format: TextureFormat::R32Float,
+                    view_dimension: TextureViewDimension::D2,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 4,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::StorageTexture {
+                    access: StorageTextureAccess::WriteOnly,
+                    format: TextureFormat::Rgba32Float,
+                    view_dimension: TextureViewDimension::D2,
+                },
+                count: None,
+            },
+        ]
+    );
+
+    vec![sculpt_layout]
+}
+
+pub fn snapshot_save_layouts(render_device: &RenderDevice) -> Vec<BindGroupLayout> {
+    let snapshot_save_layout = render_device.create_bind_group_layout(
+        None,
+        &[
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::StorageTexture {
+                    access: StorageTextureAccess::ReadWrite,
+                    format: TextureFormat::Rgba32Float,
+                    view_dimension: TextureViewDimension::D2,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::StorageTexture {
+                    access: StorageTextureAccess::WriteOnly,
+                    format: TextureFormat::Rg32Float,
+                    view_dimension: TextureViewDimension::D2,
+                },
+                count: None,
+            },
+        ]
+    );
+
+    vec![snapshot_save_layout]
+}
+
+pub fn snapshot_restore_layouts(render_device: &RenderDevice) -> Vec<BindGroupLayout> {
+    let snapshot_restore_layout = render_device.create_bind_group_layout(
+        None,
+        &[
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::StorageTexture {
+                    access: StorageTextureAccess::ReadWrite,
+                    format: TextureFormat::Rgba32Float,
+                    view_dimension: TextureViewDimension::D2,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: false },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+        ]
+    );
+
+    vec![snapshot_restore_layout]
+}
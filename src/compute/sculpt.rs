@@ -0,0 +1,322 @@
+use std::sync::{mpsc::{Receiver, Sender}, Arc, Mutex};
+
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::ExtractResource,
+        render_resource::{Buffer, BufferDescriptor, BufferUsages, CommandEncoderDescriptor, Maintain, MapMode, ShaderType, UniformBuffer},
+        renderer::{RenderDevice, RenderQueue},
+    },
+    window::PrimaryWindow,
+};
+use bevy_panorbit_camera::PanOrbitCamera;
+
+use crate::{material::MountainMaterial, terrain::{MountainTerrain, MountainTerrainSettings}};
+
+use super::{history::SnapshotHeightmap, stage::{MountainComputeQueue, PICK_STAGE, SCULPT_STAGE}, uniforms::{MountainComputeSettings, MountainHeightChanged}};
+
+// NOTE: Discriminants are matched by the `BRUSH_*` constants in the sculpt shader.
+#[derive(Reflect, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SculptBrush {
+    #[default]
+    Raise = 0,
+    Lower = 1,
+    Smooth = 2,
+    Flatten = 3,
+    Noise = 4,
+    Pinch = 5,
+    ErosionMask = 6,
+    EraseErosionMask = 7,
+}
+
+impl SculptBrush {
+    pub fn paints_mask(self) -> bool {
+        matches!(self, SculptBrush::ErosionMask | SculptBrush::EraseErosionMask)
+    }
+}
+
+#[derive(Resource, Reflect, Clone)]
+#[reflect(Resource)]
+pub struct MountainSculptSettings {
+    pub enabled: bool,
+    pub brush: SculptBrush,
+    // World units.
+    pub radius: f32,
+    // Normalized height per second at the brush centre.
+    pub strength: f32,
+    // 0 is a hard edge, 1 fades out all the way from the centre.
+    pub falloff: f32,
+    // Noise frequency per texel.
+    pub noise_scale: f32,
+}
+
+impl Default for MountainSculptSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            brush: SculptBrush::Raise,
+            radius: 8.0,
+            strength: 0.05,
+            falloff: 0.7,
+            noise_scale: 0.05,
+        }
+    }
+}
+
+// Rebuilt every frame from the cursor ray, `pick` marches it against the heightmap on the GPU
+// and `apply` stamps the brush at the hit in the same frame.
+#[derive(Resource, ExtractResource, ShaderType, Clone, Default, PartialEq)]
+pub struct MountainSculptStamp {
+    pub ray_origin: Vec3,
+    pub world_size: f32,
+    pub ray_direction: Vec3,
+    pub terrain_height: f32,
+    pub brush: u32,
+    // Texels.
+    pub radius: f32,
+    // Already scaled by the frame time.
+    pub strength: f32,
+    pub falloff: f32,
+    pub noise_scale: f32,
+    pub seed: u32,
+    pub pick: u32,
+    pub apply: u32,
+}
+
+// World space position under the cursor, from the last GPU pick.
+#[derive(Resource, Default)]
+pub struct MountainSculptCursor(pub Option<Vec3>);
+
+#[allow(clippy::too_many_arguments)]
+pub fn update_sculpt_stamp(
+    settings: Res<MountainSculptSettings>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    time: Res<Time>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    mut cameras: Query<(&Camera, &GlobalTransform, &mut PanOrbitCamera)>,
+    terrain: Res<MountainTerrain>,
+    materials: Res<Assets<MountainMaterial>>,
+    terrain_settings: Res<MountainTerrainSettings>,
+    mut compute_settings: ResMut<MountainComputeSettings>,
+    mut stamp: ResMut<MountainSculptStamp>,
+    mut queue: ResMut<MountainComputeQueue>,
+    mut height_evw: EventWriter<MountainHeightChanged>,
+    mut snapshot_evw: EventWriter<SnapshotHeightmap>,
+    mut stroke: Local<Option<u32>>,
+    mut strokes: Local<u32>,
+) {
+    let painting = settings.enabled && mouse.pressed(MouseButton::Left);
+
+    let mut ray = None;
+    for (camera, transform, mut orbit) in cameras.iter_mut() {
+        if orbit.enabled == painting {
+            orbit.enabled = !painting;
+        }
+
+        if let Some(cursor) = windows.get_single().ok().and_then(|window| window.cursor_position()) {
+            ray = ray.or(camera.viewport_to_world(transform, cursor));
+        }
+    }
+
+    // Re-bake the derived maps once per stroke rather than every stamp.
+    match (*stroke, painting) {
+        (None, true) => {
+            *strokes += 1;
+            *stroke = Some(*strokes);
+            if !settings.brush.paints_mask() {
+                snapshot_evw.send(SnapshotHeightmap);
+            } else if !compute_settings.masked_erosion {
+                compute_settings.masked_erosion = true;
+            }
+        }
+        (Some(_), false) => {
+            *stroke = None;
+            if !settings.brush.paints_mask() {
+                height_evw.send(MountainHeightChanged);
+            }
+        }
+        _ => (),
+    }
+
+    let terrain_height = materials.get(&terrain.material).map_or(0.0, |mat| mat.settings.terrain_height);
+
+    let new_stamp = match ray {
+        Some(ray) if settings.enabled => MountainSculptStamp {
+            ray_origin: ray.origin,
+            world_size: terrain_settings.world_size,
+            ray_direction: *ray.direction,
+            terrain_height,
+            brush: settings.brush as u32,
+            radius: (settings.radius / terrain_settings.world_size * compute_settings.map_size as f32).max(1.0),
+            strength: settings.strength * time.delta_seconds(),
+            falloff: settings.falloff.clamp(0.0, 1.0),
+            noise_scale: settings.noise_scale,
+            seed: stroke.unwrap_or(0),
+            pick: 1,
+            apply: painting as u32,
+        },
+        _ => MountainSculptStamp::default(),
+    };
+
+    if new_stamp.pick != 0 {
+        queue.request(PICK_STAGE);
+    }
+    if new_stamp.apply != 0 {
+        queue.request(SCULPT_STAGE);
+    }
+
+    stamp.set_if_neq(new_stamp);
+}
+
+pub fn draw_sculpt_cursor(
+    settings: Res<MountainSculptSettings>,
+    cursor: Res<MountainSculptCursor>,
+    mut gizmos: Gizmos,
+) {
+    if !settings.enabled {
+        return;
+    }
+
+    if let Some(position) = cursor.0 {
+        gizmos.circle(position, Direction3d::Y, settings.radius, Color::WHITE);
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct MountainSculptUniforms {
+    pub buf: UniformBuffer<MountainSculptStamp>,
+}
+
+pub fn prepare_sculpt_uniforms(
+    mut uniforms: ResMut<MountainSculptUniforms>,
+    stamp: Res<MountainSculptStamp>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    *uniforms.buf.get_mut() = stamp.clone();
+    uniforms.buf.write_buffer(&render_device, &render_queue);
+}
+
+// Staging buffers the pick result is read back through, a frame or more after its copy.
+const PICK_STAGING_BUFFERS: usize = 3;
+
+struct PickStaging {
+    buffer: Buffer,
+    // Set by the map callback to whether mapping succeeded, `None` while the copy is in flight.
+    mapped: Arc<Mutex<Option<bool>>>,
+    in_flight: bool,
+}
+
+#[derive(Resource)]
+pub struct MountainSculptBuffers {
+    pub pick: Buffer,
+    staging: Vec<PickStaging>,
+    // Oldest staging buffer, the next one to reuse.
+    next: usize,
+}
+
+impl FromWorld for MountainSculptBuffers {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        Self {
+            pick: render_device.create_buffer(&BufferDescriptor {
+                label: Some("mountain_sculpt_pick_buffer"),
+                size: Vec4::min_size().get(),
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            }),
+            staging: (0..PICK_STAGING_BUFFERS).map(|_| PickStaging {
+                buffer: render_device.create_buffer(&BufferDescriptor {
+                    label: Some("mountain_sculpt_staging_buffer"),
+                    size: Vec4::min_size().get(),
+                    usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+                mapped: Arc::new(Mutex::new(None)),
+                in_flight: false,
+            }).collect(),
+            next: 0,
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct MountainSculptPickSender(pub Mutex<Sender<Option<Vec3>>>);
+
+#[derive(Resource)]
+pub struct MountainSculptPickReceiver(pub Mutex<Receiver<Option<Vec3>>>);
+
+// Sends the picks whose staging buffer has mapped since the last frame, then copies this frame's
+// pick into the oldest free staging buffer. Picks are dropped while every buffer is in flight.
+pub fn readback_sculpt_pick(
+    stamp: Res<MountainSculptStamp>,
+    buffers: Option<ResMut<MountainSculptBuffers>>,
+    sender: Res<MountainSculptPickSender>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let Some(mut buffers) = buffers else { return };
+    let buffers = buffers.as_mut();
+
+    if buffers.staging.iter().any(|staging| staging.in_flight) {
+        render_device.poll(Maintain::Poll);
+    }
+
+    // Oldest first, so a later pick never gets overwritten by an earlier one.
+    for i in 0..PICK_STAGING_BUFFERS {
+        let staging = &mut buffers.staging[(buffers.next + i) % PICK_STAGING_BUFFERS];
+        if !staging.in_flight {
+            continue;
+        }
+
+        let Some(ok) = staging.mapped.lock().unwrap().take() else { continue };
+        staging.in_flight = false;
+        if !ok {
+            error!("Failed to map sculpt pick buffer");
+            continue;
+        }
+
+        let hit = {
+            let data = staging.buffer.slice(..).get_mapped_range();
+            let values: Vec<f32> = data.chunks_exact(4).map(|v| f32::from_le_bytes(v.try_into().unwrap())).collect();
+            (values[3] != 0.0).then(|| Vec3::new(values[0], values[1], values[2]))
+        };
+        staging.buffer.unmap();
+
+        let _ = sender.0.lock().unwrap().send(hit);
+    }
+
+    let staging = &mut buffers.staging[buffers.next];
+    if stamp.pick == 0 || staging.in_flight {
+        return;
+    }
+
+    let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("mountain_sculpt_pick_encoder"),
+    });
+    encoder.copy_buffer_to_buffer(&buffers.pick, 0, &staging.buffer, 0, Vec4::min_size().get());
+    render_queue.submit([encoder.finish()]);
+
+    let mapped = staging.mapped.clone();
+    staging.buffer.slice(..).map_async(MapMode::Read, move |result| {
+        *mapped.lock().unwrap() = Some(result.is_ok());
+    });
+    staging.in_flight = true;
+    buffers.next = (buffers.next + 1) % PICK_STAGING_BUFFERS;
+}
+
+pub fn receive_sculpt_pick(
+    receiver: Res<MountainSculptPickReceiver>,
+    settings: Res<MountainSculptSettings>,
+    mut cursor: ResMut<MountainSculptCursor>,
+) {
+    if !settings.enabled {
+        cursor.0 = None;
+    }
+
+    let receiver = receiver.0.lock().unwrap();
+    while let Ok(hit) = receiver.try_recv() {
+        cursor.0 = hit;
+    }
+}
@@ -0,0 +1,170 @@
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::ExtractResource,
+        render_resource::{BindGroup, BindGroupLayout},
+        renderer::RenderDevice,
+        texture::GpuImage,
+    },
+    utils::{HashMap, HashSet},
+};
+
+use super::{history::{HistoryOp, MountainHistoryOps}, node::MountainComputeReady, uniforms::MountainComputeTextures};
+
+pub type StageLabel = &'static str;
+
+pub const SNAPSHOT_SAVE_STAGE: StageLabel = "snapshot_save";
+pub const SNAPSHOT_RESTORE_STAGE: StageLabel = "snapshot_restore";
+pub const FBM_STAGE: StageLabel = "fbm";
+pub const SHADOW_STAGE: StageLabel = "shadow";
+pub const AO_STAGE: StageLabel = "ambient_occlusion";
+pub const EROSION_STAGE: StageLabel = "erosion";
+pub const PICK_STAGE: StageLabel = "sculpt_pick";
+pub const SCULPT_STAGE: StageLabel = "sculpt";
+pub const PREPARE_WRITE_STAGE: StageLabel = "prepare_write";
+
+// What a stage's callbacks build its bind groups and dispatches from, in the render world.
+pub struct StageContext<'a> {
+    pub world: &'a World,
+    pub render_device: &'a RenderDevice,
+    // Created by the stage's `layouts` callback.
+    pub layouts: &'a [BindGroupLayout],
+    // The current heightmap.
+    pub map: &'a GpuImage,
+    // Destination of ping pong stages, which becomes the current heightmap after the pass.
+    pub map_back: &'a GpuImage,
+    // Whether `schedule_compute_stages` picked the stage for this frame.
+    pub queued: bool,
+}
+
+pub struct StageDispatch {
+    pub workgroups: UVec3,
+    // Dynamic offsets of each bind group, groups past the end have none.
+    pub offsets: Vec<Vec<u32>>,
+}
+
+impl StageDispatch {
+    pub fn new(workgroups: UVec3) -> Self {
+        Self { workgroups, offsets: Vec::new() }
+    }
+}
+
+#[derive(Clone)]
+pub struct MountainComputeStage {
+    pub label: StageLabel,
+    // Asset path of the WGSL shader.
+    pub shader: &'static str,
+    pub entry_point: &'static str,
+    // One layout per bind group, created once when the pipeline is queued.
+    pub layouts: fn(&RenderDevice) -> Vec<BindGroupLayout>,
+    // The stage is skipped for the frame when this returns `None`.
+    pub bind_groups: fn(&StageContext) -> Option<Vec<BindGroup>>,
+    // Called every frame, stages driven by the queue return nothing unless `queued` is set.
+    pub dispatch: fn(&StageContext) -> Vec<StageDispatch>,
+    // The pass reads `map` and writes every texel of `map_back`, which are swapped afterwards. The
+    // main world swaps its side when it schedules the stage, so these have to run whenever queued.
+    pub ping_pong: bool,
+}
+
+// Every pass the compute node can run, in the order it runs them. Pipelines are queued in
+// `finish`, so stages have to be added while plugins build.
+#[derive(Resource, Default, Clone)]
+pub struct MountainComputeStages(pub Vec<MountainComputeStage>);
+
+// How often each stage has been requested, a stage runs once whenever its count moves.
+#[derive(Resource, Default, Clone)]
+pub struct MountainComputeQueue {
+    requested: HashMap<StageLabel, u32>,
+    // Counts each stage was last scheduled at.
+    scheduled: HashMap<StageLabel, u32>,
+}
+
+impl MountainComputeQueue {
+    pub fn request(&mut self, label: StageLabel) {
+        let count = self.requested.entry(label).or_default();
+        *count = count.wrapping_add(1);
+    }
+
+    pub fn count(&self, label: StageLabel) -> u32 {
+        self.requested.get(label).copied().unwrap_or(0)
+    }
+
+    // Whether the stage was requested and has yet to be scheduled. Once it isn't, the frame it was
+    // scheduled in has rendered with its result.
+    pub fn is_pending(&self, label: StageLabel) -> bool {
+        self.scheduled.get(label).copied().unwrap_or(0) != self.count(label)
+    }
+}
+
+// What the compute node runs this frame, decided in the main world so it always knows which map
+// the node leaves current.
+#[derive(Resource, ExtractResource, Default, Clone)]
+pub struct MountainComputeRuns {
+    pub stages: HashSet<StageLabel>,
+    // Copy for the snapshot stages, nothing else runs alongside it.
+    pub history: Option<HistoryOp>,
+    // Index of the current map before this frame's passes.
+    pub front: usize,
+}
+
+impl MountainComputeRuns {
+    fn is_idle(&self) -> bool {
+        self.stages.is_empty() && self.history.is_none()
+    }
+}
+
+// Runs after everything that requests stages. History ops go first, one per frame, and hold back
+// every other stage until they're done. Each queued ping pong stage swaps the maps right away, so
+// `MountainComputeTextures::map` is what the node leaves behind this frame.
+pub fn schedule_compute_stages(
+    stages: Res<MountainComputeStages>,
+    mut queue: ResMut<MountainComputeQueue>,
+    ready: Res<MountainComputeReady>,
+    mut history_ops: ResMut<MountainHistoryOps>,
+    mut textures: ResMut<MountainComputeTextures>,
+    mut runs: ResMut<MountainComputeRuns>,
+) {
+    let mut next = MountainComputeRuns { front: textures.front(), ..default() };
+
+    if ready.get() {
+        if let Some(op) = history_ops.0.pop_front() {
+            next.history = Some(op);
+        } else {
+            for stage in stages.0.iter() {
+                let count = queue.count(stage.label);
+                if queue.scheduled.insert(stage.label, count).unwrap_or(0) != count {
+                    next.stages.insert(stage.label);
+                    if stage.ping_pong {
+                        textures.swap();
+                    }
+                }
+            }
+        }
+    }
+
+    // Only extracted when it changes, so an idle frame has to replace a busy one.
+    if !next.is_idle() || !runs.is_idle() {
+        *runs = next;
+    }
+}
+
+pub trait MountainComputeStageAppExt {
+    fn add_compute_stage(&mut self, stage: MountainComputeStage) -> &mut Self;
+    // Requests the stage whenever `E` is sent.
+    fn queue_compute_stage_on<E: Event>(&mut self, label: StageLabel) -> &mut Self;
+}
+
+impl MountainComputeStageAppExt for App {
+    fn add_compute_stage(&mut self, stage: MountainComputeStage) -> &mut Self {
+        self.world.get_resource_or_insert_with(MountainComputeStages::default).0.push(stage);
+        self
+    }
+
+    fn queue_compute_stage_on<E: Event>(&mut self, label: StageLabel) -> &mut Self {
+        self.add_systems(PostUpdate, (move |mut evr: EventReader<E>, mut queue: ResMut<MountainComputeQueue>| {
+            if evr.read().count() > 0 {
+                queue.request(label);
+            }
+        }).before(schedule_compute_stages))
+    }
+}
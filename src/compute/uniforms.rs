@@ -4,22 +4,24 @@ use bevy::{
         extract_resource::ExtractResource,
         render_asset::RenderAssetUsages,
         render_resource::{
-            Extent3d, FilterMode, SamplerDescriptor, ShaderType, StorageBuffer,
+            DynamicUniformBuffer, Extent3d, FilterMode, SamplerDescriptor, ShaderType, StorageBuffer,
             TextureDimension, TextureFormat, TextureUsages, UniformBuffer,
         },
         renderer::{RenderDevice, RenderQueue},
         texture::ImageSampler,
     },
 };
+use serde::{Deserialize, Serialize};
 
-use super::{node::{MountainErosionStatus, MountainGenerateFBMStatus, MountainGenerateShadowStatus, MountainPrepareWriteStatus}, TEXTURE_SIZE};
+use super::{node::{MountainComputeReady, MountainErosionStatus}, stage::{MountainComputeQueue, EROSION_STAGE}, DROPLETS_PER_DISPATCH, DROPLETS_PER_WORKGROUP, MASK_SIZE, TEXTURE_SIZE};
 
 pub const EROSION_RADIUS: i32 = 3;
 // NOTE: Make sure to change value in shader if this is changed.
 pub const BRUSH_STORAGE_LENGTH: u32 = 64; // Actually 49 (2 * EROSION_RADIUS + 1) ^ 2
 
-#[derive(Clone, Resource, ExtractResource, Reflect, ShaderType)]
+#[derive(Clone, Resource, ExtractResource, Reflect, Serialize, Deserialize)]
 #[reflect(Resource)]
+#[serde(default)]
 pub struct MountainComputeSettings {
     pub map_size: u32,
 
@@ -32,7 +34,7 @@ pub struct MountainComputeSettings {
     pub strength: f32,
     pub center: Vec2,
 
-    time: f32,
+    pub seed: u32,
     brush_length: u32,
 
     pub sun_direction: Vec3,
@@ -48,8 +50,108 @@ pub struct MountainComputeSettings {
     pub gravity: f32,
     pub start_speed: f32,
     pub start_water: f32,
-    
-    _padding: Vec2,
+
+    pub iteration: u32,
+    // Angular radius of the sun in heightmap space, sets the penumbra width.
+    pub sun_size: f32,
+    pub shadow_steps: u32,
+    // Fraction of the clearance above the terrain to step each march iteration.
+    pub shadow_step_scale: f32,
+
+    pub ao_directions: u32,
+    pub ao_steps: u32,
+    // Search radius of the horizon march in texels.
+    pub ao_radius: f32,
+    // `terrain_height / world_size`, kept in sync with the terrain by the material so it isn't
+    // exposed to the inspector.
+    #[reflect(ignore)]
+    pub ao_height_scale: f32,
+
+    // Only spawn droplets where the painted erosion mask allows it, set by painting or importing a mask.
+    pub masked_erosion: bool,
+}
+
+// `MountainComputeSettings` as the compute shaders bind it, with flags as u32.
+#[derive(Clone, Default, ShaderType)]
+pub struct MountainComputeUniform {
+    pub map_size: u32,
+
+    pub num_octaves: u32,
+    pub roughness: f32,
+    pub lacunarity: f32,
+    pub persistence: f32,
+    pub sharpness: f32,
+    pub offset: f32,
+    pub strength: f32,
+    pub center: Vec2,
+
+    pub seed: u32,
+    pub brush_length: u32,
+
+    pub sun_direction: Vec3,
+
+    pub max_lifetime: u32,
+    pub erosion_radius: i32,
+    pub inertia: f32,
+    pub sediment_capacity_factor: f32,
+    pub min_sediment_capacity: f32,
+    pub erode_speed: f32,
+    pub deposit_speed: f32,
+    pub evaporation_speed: f32,
+    pub gravity: f32,
+    pub start_speed: f32,
+    pub start_water: f32,
+
+    pub iteration: u32,
+    pub sun_size: f32,
+    pub shadow_steps: u32,
+    pub shadow_step_scale: f32,
+
+    pub ao_directions: u32,
+    pub ao_steps: u32,
+    pub ao_radius: f32,
+    pub ao_height_scale: f32,
+
+    pub masked_erosion: u32,
+}
+
+impl From<&MountainComputeSettings> for MountainComputeUniform {
+    fn from(settings: &MountainComputeSettings) -> Self {
+        Self {
+            map_size: settings.map_size,
+            num_octaves: settings.num_octaves,
+            roughness: settings.roughness,
+            lacunarity: settings.lacunarity,
+            persistence: settings.persistence,
+            sharpness: settings.sharpness,
+            offset: settings.offset,
+            strength: settings.strength,
+            center: settings.center,
+            seed: settings.seed,
+            brush_length: settings.brush_length,
+            sun_direction: settings.sun_direction.normalize(),
+            max_lifetime: settings.max_lifetime,
+            erosion_radius: settings.erosion_radius,
+            inertia: settings.inertia,
+            sediment_capacity_factor: settings.sediment_capacity_factor,
+            min_sediment_capacity: settings.min_sediment_capacity,
+            erode_speed: settings.erode_speed,
+            deposit_speed: settings.deposit_speed,
+            evaporation_speed: settings.evaporation_speed,
+            gravity: settings.gravity,
+            start_speed: settings.start_speed,
+            start_water: settings.start_water,
+            iteration: settings.iteration,
+            sun_size: settings.sun_size,
+            shadow_steps: settings.shadow_steps,
+            shadow_step_scale: settings.shadow_step_scale,
+            ao_directions: settings.ao_directions,
+            ao_steps: settings.ao_steps,
+            ao_radius: settings.ao_radius,
+            ao_height_scale: settings.ao_height_scale,
+            masked_erosion: settings.masked_erosion as u32,
+        }
+    }
 }
 
 impl Default for  MountainComputeSettings {
@@ -75,7 +177,7 @@ impl Default for  MountainComputeSettings {
             strength: 1.0,
             center: Vec2::new(0.5, -0.5),
 
-            time: 0.0,
+            seed: 0,
             brush_length: BRUSH_STORAGE_LENGTH,
 
             sun_direction: Vec3::new(1.0, 4.0, 0.5).normalize(),
@@ -92,14 +194,40 @@ impl Default for  MountainComputeSettings {
             start_speed: 1.0,
             start_water: 1.0,
 
-            _padding: Vec2::ZERO,
+            iteration: 0,
+            sun_size: 0.02,
+            shadow_steps: 128,
+            shadow_step_scale: 0.05,
+
+            ao_directions: 8,
+            ao_steps: 12,
+            ao_radius: 64.0,
+            ao_height_scale: 60.0 / 256.0,
+
+            masked_erosion: false,
         }
     }
 }
 
+impl MountainComputeSettings {
+    fn shadow_inputs_changed(&self, other: &Self) -> bool {
+        self.sun_direction != other.sun_direction
+            || self.sun_size != other.sun_size
+            || self.shadow_steps != other.shadow_steps
+            || self.shadow_step_scale != other.shadow_step_scale
+    }
+
+    fn ao_inputs_changed(&self, other: &Self) -> bool {
+        self.ao_directions != other.ao_directions
+            || self.ao_steps != other.ao_steps
+            || self.ao_radius != other.ao_radius
+            || self.ao_height_scale != other.ao_height_scale
+    }
+}
+
 #[derive(Resource, Default)]
 pub struct MountainComputeUniforms {
-    pub buf: UniformBuffer<MountainComputeSettings>,
+    pub buf: UniformBuffer<MountainComputeUniform>,
 }
 
 pub fn prepare_uniforms(
@@ -107,90 +235,369 @@ pub fn prepare_uniforms(
     general_settings: Res<MountainComputeSettings>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
-    time: Res<Time>,
 ) {
-    let general = uniforms.buf.get_mut();
-    *general = general_settings.clone();
-
-    general.time = time.elapsed_seconds();
-    if !general.sun_direction.is_normalized() {
-        general.sun_direction = general.sun_direction.normalize();
-    }
-
+    *uniforms.buf.get_mut() = general_settings.as_ref().into();
     uniforms.buf.write_buffer(&render_device, &render_queue);
 }
 
 #[derive(Event)]
 pub struct RegenerateMountain;
 
-pub fn update_generate_fbm_status(
+pub fn reset_erosion_progress(
     mut evr: EventReader<RegenerateMountain>,
-    mut status: ResMut<MountainGenerateFBMStatus>,
+    mut settings: ResMut<MountainComputeSettings>,
+    mut progress: ResMut<MountainErosionProgress>,
 ) {
     for _ev in evr.read() {
-        *status = MountainGenerateFBMStatus::Update;
+        settings.iteration = 0;
+        progress.total = 0;
     }
 }
 
 #[derive(Event)]
 pub struct RegenerateShadows;
 
-pub fn update_generate_shadow_status(
-    mut evr: EventReader<RegenerateShadows>,
-    mut status: ResMut<MountainGenerateShadowStatus>,
-) {
-    for _ev in evr.read() {
-        *status = MountainGenerateShadowStatus::Update;
+#[derive(Event)]
+pub struct RegenerateAmbientOcclusion;
+
+#[derive(Resource, Reflect, Clone)]
+#[reflect(Resource)]
+pub struct MountainBakeSettings {
+    // Re-bake shadows and occlusion when the heights or their inputs change.
+    pub auto_bake: bool,
+    // Erosion iterations between re-bakes while erosion runs, 0 waits until it stops.
+    pub erosion_interval: u32,
+}
+
+impl Default for MountainBakeSettings {
+    fn default() -> Self {
+        Self {
+            auto_bake: true,
+            erosion_interval: 64,
+        }
     }
 }
 
+// Sent by anything writing the height channel outside of the FBM and erosion passes.
 #[derive(Event)]
-pub struct PrepareWriteCompute;
-
-pub fn update_prepare_write_status(
-    mut evr: EventReader<PrepareWriteCompute>,
-    mut status: ResMut<MountainPrepareWriteStatus>,
+pub struct MountainHeightChanged;
+
+#[allow(clippy::too_many_arguments)]
+pub fn invalidate_derived_maps(
+    bake_settings: Res<MountainBakeSettings>,
+    settings: Res<MountainComputeSettings>,
+    erosion_status: Res<MountainErosionStatus>,
+    mut height_evr: EventReader<MountainHeightChanged>,
+    mut shadow_evw: EventWriter<RegenerateShadows>,
+    mut ao_evw: EventWriter<RegenerateAmbientOcclusion>,
+    mut baked: Local<Option<MountainComputeSettings>>,
+    mut height_dirty: Local<bool>,
 ) {
-    for _ev in evr.read() {
-        *status = MountainPrepareWriteStatus::Update;
+    *height_dirty |= height_evr.read().count() > 0;
+
+    let Some(baked) = baked.as_mut() else {
+        *baked = Some(settings.clone());
+        return;
+    };
+
+    // Regenerating the heightmap bakes everything and restarts the iteration count.
+    if settings.iteration < baked.iteration {
+        baked.iteration = settings.iteration;
+    }
+
+    if !bake_settings.auto_bake {
+        return;
+    }
+
+    let eroded = settings.iteration - baked.iteration;
+    let height_stale = *height_dirty || eroded > 0 && (*erosion_status == MountainErosionStatus::Wait
+        || bake_settings.erosion_interval > 0 && eroded >= bake_settings.erosion_interval);
+
+    let shadow = height_stale || settings.shadow_inputs_changed(baked);
+    let ao = height_stale || settings.ao_inputs_changed(baked);
+
+    if shadow {
+        shadow_evw.send(RegenerateShadows);
+    }
+    if ao {
+        ao_evw.send(RegenerateAmbientOcclusion);
+    }
+    if shadow || ao {
+        *baked = settings.clone();
+        *height_dirty = false;
     }
 }
 
+#[derive(Event)]
+pub struct PrepareWriteCompute;
+
 #[allow(dead_code)]
 #[derive(Event)]
 pub enum MountainErosionTrigger {
     Start,
     Stop,
-    Toggle
+    Toggle,
+    // Erodes until this many droplets have been simulated, then stops and sends `ErosionFinished`.
+    Run { droplets: u64 },
+}
+
+#[derive(Event)]
+pub struct ErosionFinished {
+    pub droplets: u64,
+}
+
+#[derive(Resource, Reflect, Default, Clone)]
+#[reflect(Resource)]
+pub struct MountainErosionProgress {
+    // Droplets simulated since erosion was last started. Droplets spawned outside the erosion mask
+    // are dispatched and counted even though they return without eroding.
+    pub droplets: u64,
+    // Budget of the current `MountainErosionTrigger::Run`.
+    pub target: Option<u64>,
+    // `droplets / target`, 0 while eroding without a budget.
+    pub fraction: f32,
+    // Droplets simulated since the heightmap was generated.
+    pub total: u64,
+}
+
+impl MountainErosionProgress {
+    fn update_fraction(&mut self) {
+        self.fraction = self.target.map_or(0.0, |target| (self.droplets as f64 / target.max(1) as f64).min(1.0) as f32);
+    }
 }
 
 pub fn update_erosion_status(
     mut evr: EventReader<MountainErosionTrigger>,
     mut status: ResMut<MountainErosionStatus>,
+    mut progress: ResMut<MountainErosionProgress>,
 ) {
     for ev in evr.read() {
-        match ev {
-            MountainErosionTrigger::Start => *status = MountainErosionStatus::Update,
-            MountainErosionTrigger::Stop => *status = MountainErosionStatus::Wait,
+        let target = match ev {
+            MountainErosionTrigger::Start => {
+                *status = MountainErosionStatus::Update;
+                None
+            }
+            MountainErosionTrigger::Stop => {
+                *status = MountainErosionStatus::Wait;
+                continue;
+            }
             MountainErosionTrigger::Toggle => if *status == MountainErosionStatus::Wait {
-                *status = MountainErosionStatus::Update
+                *status = MountainErosionStatus::Update;
+                None
             } else {
-                *status = MountainErosionStatus::Wait
+                *status = MountainErosionStatus::Wait;
+                continue;
+            }
+            MountainErosionTrigger::Run { droplets } => {
+                *status = MountainErosionStatus::Update;
+                Some(*droplets)
             }
+        };
+
+        progress.droplets = 0;
+        progress.target = target;
+        progress.update_fraction();
+    }
+}
+
+#[derive(Reflect, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErosionBatchMode {
+    #[default]
+    Fixed,
+    // Grows or shrinks `droplets_per_dispatch` to keep frames near `frame_budget_ms`.
+    Adaptive,
+}
+
+#[derive(Resource, Reflect, Clone)]
+#[reflect(Resource)]
+pub struct MountainErosionBatchSettings {
+    pub mode: ErosionBatchMode,
+    // Rounded up to whole workgroups, adjusted automatically in adaptive mode.
+    pub droplets_per_dispatch: u32,
+    pub max_droplets_per_dispatch: u32,
+    pub frame_budget_ms: f32,
+    // Runs several dispatches back to back each frame, for when the viewer doesn't need to stay responsive.
+    pub turbo: bool,
+    pub turbo_dispatches: u32,
+}
+
+impl Default for MountainErosionBatchSettings {
+    fn default() -> Self {
+        Self {
+            mode: ErosionBatchMode::Fixed,
+            droplets_per_dispatch: DROPLETS_PER_DISPATCH,
+            max_droplets_per_dispatch: DROPLETS_PER_DISPATCH * 64,
+            frame_budget_ms: 16.0,
+            turbo: false,
+            turbo_dispatches: 16,
         }
     }
 }
 
+#[derive(ShaderType, Clone, Copy, Default)]
+pub struct ErosionBatch {
+    // Global index of the batch's first droplet, each droplet is seeded from its own index so the
+    // spawns don't depend on how droplets are split into batches. Wraps after 2^32 droplets.
+    pub first_droplet: u32,
+    pub droplets: u32,
+}
+
+impl ErosionBatch {
+    pub fn workgroups(&self) -> u32 {
+        self.droplets.div_ceil(DROPLETS_PER_WORKGROUP)
+    }
+}
+
+// Erosion dispatches for this frame, empty while erosion is stopped.
+#[derive(Resource, ExtractResource, Default, Clone)]
+pub struct MountainErosionBatches(pub Vec<ErosionBatch>);
+
+#[derive(Resource, Default)]
+pub struct MountainErosionBatchUniforms {
+    pub buf: DynamicUniformBuffer<ErosionBatch>,
+    pub offsets: Vec<u32>,
+}
+
+pub fn prepare_erosion_batches(
+    mut uniforms: ResMut<MountainErosionBatchUniforms>,
+    batches: Res<MountainErosionBatches>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let uniforms = uniforms.as_mut();
+    uniforms.buf.clear();
+    uniforms.offsets = batches.0.iter().map(|batch| uniforms.buf.push(batch)).collect();
+    uniforms.buf.write_buffer(&render_device, &render_queue);
+}
+
+// Larger batches would need more workgroups than a single dispatch dimension allows.
+pub fn clamp_erosion_batch(
+    render_device: Res<RenderDevice>,
+    mut batch_settings: ResMut<MountainErosionBatchSettings>,
+) {
+    let limit = render_device.limits().max_compute_workgroups_per_dimension * DROPLETS_PER_WORKGROUP;
+
+    if batch_settings.max_droplets_per_dispatch > limit {
+        batch_settings.max_droplets_per_dispatch = limit;
+    }
+    if batch_settings.droplets_per_dispatch > limit {
+        batch_settings.droplets_per_dispatch = limit;
+    }
+}
+
+pub fn enable_turbo_erosion(mut batch_settings: ResMut<MountainErosionBatchSettings>) {
+    if crate::launch_flag("--turbo") {
+        batch_settings.turbo = true;
+    }
+}
+
+// Frame time stands in for the GPU time of the erosion dispatches, which dominate while eroding.
+pub fn adapt_erosion_batch(
+    time: Res<Time>,
+    status: Res<MountainErosionStatus>,
+    mut batch_settings: ResMut<MountainErosionBatchSettings>,
+) {
+    if batch_settings.mode != ErosionBatchMode::Adaptive || *status != MountainErosionStatus::Update {
+        return;
+    }
+
+    let frame_ms = time.delta_seconds() * 1000.0;
+    let budget = batch_settings.frame_budget_ms;
+    let scale = if frame_ms > budget * 1.1 {
+        0.8
+    } else if frame_ms < budget * 0.9 {
+        1.1
+    } else {
+        return;
+    };
+
+    let max = batch_settings.max_droplets_per_dispatch.max(DROPLETS_PER_WORKGROUP);
+    let droplets = ((batch_settings.droplets_per_dispatch as f32 * scale) as u32).clamp(DROPLETS_PER_WORKGROUP, max);
+
+    if batch_settings.droplets_per_dispatch != droplets {
+        batch_settings.droplets_per_dispatch = droplets;
+    }
+}
+
+// Runs after the status systems, so the batches counted here are the ones dispatched this frame.
+#[allow(clippy::too_many_arguments)]
+pub fn update_erosion_iteration(
+    mut status: ResMut<MountainErosionStatus>,
+    ready: Res<MountainComputeReady>,
+    batch_settings: Res<MountainErosionBatchSettings>,
+    mut settings: ResMut<MountainComputeSettings>,
+    mut progress: ResMut<MountainErosionProgress>,
+    mut batches: ResMut<MountainErosionBatches>,
+    mut queue: ResMut<MountainComputeQueue>,
+    mut finished_evw: EventWriter<ErosionFinished>,
+) {
+    if *status != MountainErosionStatus::Update || !ready.get() {
+        if !batches.0.is_empty() {
+            batches.0.clear();
+        }
+        return;
+    }
+
+    if let Some(target) = progress.target {
+        if progress.droplets >= target {
+            *status = MountainErosionStatus::Wait;
+            progress.target = None;
+            batches.0.clear();
+            finished_evw.send(ErosionFinished { droplets: progress.droplets });
+            info!("Finished eroding {} droplets", progress.droplets);
+            return;
+        }
+    }
+
+    let dispatches = if batch_settings.turbo { batch_settings.turbo_dispatches.max(1) } else { 1 };
+    let per_dispatch = batch_settings.droplets_per_dispatch.max(1).div_ceil(DROPLETS_PER_WORKGROUP) * DROPLETS_PER_WORKGROUP;
+
+    batches.0.clear();
+    for _ in 0..dispatches {
+        let remaining = progress.target.map_or(u64::MAX, |target| target - progress.droplets);
+        if remaining == 0 {
+            break;
+        }
+
+        let droplets = remaining.min(per_dispatch as u64) as u32;
+        batches.0.push(ErosionBatch { first_droplet: progress.total as u32, droplets });
+
+        settings.iteration += 1;
+        progress.droplets += droplets as u64;
+        progress.total += droplets as u64;
+    }
+    progress.update_fraction();
+
+    queue.request(EROSION_STAGE);
+}
+
 
 #[derive(Resource, ExtractResource, Clone)]
 pub struct MountainComputeTextures {
-    pub map: Handle<Image>
+    // Ping pong pair, a pass reads the current map and writes the other, then they swap.
+    pub maps: [Handle<Image>; 2],
+    // Index of the current map, owned by the main world and swapped by `schedule_compute_stages`.
+    front: usize,
+    pub export: Handle<Image>,
+    pub erosion_mask: Handle<Image>,
 }
 
-pub fn setup_textures(
-    mut commands: Commands,
-    mut images: ResMut<Assets<Image>>,
-) {
+impl MountainComputeTextures {
+    // The heightmap as of this frame's passes, what everything outside the compute node should bind.
+    pub fn map(&self) -> &Handle<Image> {
+        &self.maps[self.front]
+    }
+
+    pub fn front(&self) -> usize {
+        self.front
+    }
+
+    pub(super) fn swap(&mut self) {
+        self.front = 1 - self.front;
+    }
+}
+
+fn create_map_texture(asset_usage: RenderAssetUsages) -> Image {
     let extent = Extent3d {
         width: TEXTURE_SIZE,
         height: TEXTURE_SIZE,
@@ -202,7 +609,7 @@ pub fn setup_textures(
         TextureDimension::D2,
         &[0; 16],
         TextureFormat::Rgba32Float,
-        RenderAssetUsages::RENDER_WORLD | RenderAssetUsages::MAIN_WORLD,
+        asset_usage,
     );
 
     im.texture_descriptor.usage = TextureUsages::COPY_DST | TextureUsages::STORAGE_BINDING 
@@ -213,8 +620,39 @@ pub fn setup_textures(
         ..default()
     }.into());
 
+    im
+}
+
+fn create_mask_texture() -> Image {
+    let mut im = Image::new_fill(
+        Extent3d {
+            width: MASK_SIZE,
+            height: MASK_SIZE,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0; 4],
+        TextureFormat::R32Float,
+        RenderAssetUsages::RENDER_WORLD | RenderAssetUsages::MAIN_WORLD,
+    );
+
+    im.texture_descriptor.usage = TextureUsages::COPY_DST | TextureUsages::STORAGE_BINDING
+        | TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_SRC;
+    im
+}
+
+pub fn setup_textures(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+) {
     commands.insert_resource(MountainComputeTextures {
-        map: images.add(im),
+        maps: [
+            images.add(create_map_texture(RenderAssetUsages::RENDER_WORLD | RenderAssetUsages::MAIN_WORLD)),
+            images.add(create_map_texture(RenderAssetUsages::RENDER_WORLD)),
+        ],
+        front: 0,
+        export: images.add(create_map_texture(RenderAssetUsages::RENDER_WORLD)),
+        erosion_mask: images.add(create_mask_texture()),
     });
 }
 
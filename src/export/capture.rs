@@ -0,0 +1,426 @@
+use std::path::PathBuf;
+
+use bevy::{
+    core_pipeline::{core_3d::graph::Core3d, tonemapping::{DebandDither, Tonemapping}},
+    math::Vec3A,
+    prelude::*,
+    render::{
+        camera::{CameraProjection, CameraRenderGraph, Exposure, RenderTarget},
+        primitives::Frustum,
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat, TextureUsages},
+        renderer::RenderDevice,
+        view::{ColorGrading, VisibleEntities},
+    },
+};
+use bevy_panorbit_camera::PanOrbitCamera;
+use image::{ImageBuffer, Rgba};
+
+use super::readback::{MountainReadbackRequests, ReadbackComplete};
+
+pub const CAPTURE_DIR: &str = "captures";
+// Gives new render targets time to get their pipelines specialized before reading them back.
+const CAPTURE_WARMUP_FRAMES: u32 = 8;
+
+#[derive(Resource, Reflect, Clone)]
+#[reflect(Resource)]
+pub struct MountainCaptureSettings {
+    pub width: u32,
+    pub height: u32,
+    pub supersampling: u32,
+    pub exr: bool,
+}
+
+impl Default for MountainCaptureSettings {
+    fn default() -> Self {
+        Self {
+            width: 3840,
+            height: 2160,
+            supersampling: 2,
+            exr: false,
+        }
+    }
+}
+
+#[derive(Event)]
+pub struct CaptureRender;
+
+// Off-axis perspective projection covering the `min..max` part of the full view in NDC,
+// so captures larger than the maximum texture size can be rendered as tiles.
+#[derive(Component, Reflect, Clone)]
+#[reflect(Component, Default)]
+pub struct TileProjection {
+    pub fov: f32,
+    pub aspect_ratio: f32,
+    pub near: f32,
+    pub far: f32,
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+impl Default for TileProjection {
+    fn default() -> Self {
+        let perspective = PerspectiveProjection::default();
+
+        Self {
+            fov: perspective.fov,
+            aspect_ratio: perspective.aspect_ratio,
+            near: perspective.near,
+            far: perspective.far,
+            min: Vec2::NEG_ONE,
+            max: Vec2::ONE,
+        }
+    }
+}
+
+impl CameraProjection for TileProjection {
+    fn get_projection_matrix(&self) -> Mat4 {
+        let scale = 2.0 / (self.max - self.min);
+        let offset = -(self.max + self.min) / (self.max - self.min);
+        let crop = Mat4::from_cols(
+            Vec4::new(scale.x, 0.0, 0.0, 0.0),
+            Vec4::new(0.0, scale.y, 0.0, 0.0),
+            Vec4::Z,
+            Vec4::new(offset.x, offset.y, 0.0, 1.0),
+        );
+
+        crop * Mat4::perspective_infinite_reverse_rh(self.fov, self.aspect_ratio, self.near)
+    }
+
+    // The aspect ratio belongs to the full capture, not to the tile being rendered.
+    fn update(&mut self, _width: f32, _height: f32) {}
+
+    fn far(&self) -> f32 {
+        self.far
+    }
+
+    fn get_frustum_corners(&self, z_near: f32, z_far: f32) -> [Vec3A; 8] {
+        let tan_half_fov = (self.fov / 2.0).tan();
+        let corner = |x: f32, y: f32, z: f32| {
+            Vec3A::new(x * z.abs() * tan_half_fov * self.aspect_ratio, y * z.abs() * tan_half_fov, z)
+        };
+
+        [
+            corner(self.max.x, self.min.y, z_near),
+            corner(self.max.x, self.max.y, z_near),
+            corner(self.min.x, self.max.y, z_near),
+            corner(self.min.x, self.min.y, z_near),
+            corner(self.max.x, self.min.y, z_far),
+            corner(self.max.x, self.max.y, z_far),
+            corner(self.min.x, self.max.y, z_far),
+            corner(self.min.x, self.min.y, z_far),
+        ]
+    }
+}
+
+struct CaptureTile {
+    camera: Entity,
+    image: Handle<Image>,
+    offset: UVec2,
+    size: UVec2,
+    readback: Option<u32>,
+    stitched: bool,
+}
+
+#[derive(Resource)]
+pub struct MountainCapture {
+    tiles: Vec<CaptureTile>,
+    size: UVec2,
+    supersampling: u32,
+    exr: bool,
+    frames: u32,
+    // Linear RGBA at the output size, each tile is box filtered into it as its readback arrives.
+    pixels: Vec<[f32; 4]>,
+}
+
+fn next_capture_path(extension: &str) -> PathBuf {
+    (0..)
+        .map(|i| PathBuf::from(CAPTURE_DIR).join(format!("capture_{:03}.{}", i, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+pub fn create_target(size: UVec2, format: TextureFormat) -> Image {
+    let mut image = Image::new_fill(
+        Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &vec![0; format.block_copy_size(None).unwrap() as usize],
+        format,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+
+    image.texture_descriptor.usage = TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING
+        | TextureUsages::COPY_SRC | TextureUsages::COPY_DST;
+    image
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn start_capture(
+    mut commands: Commands,
+    mut evr: EventReader<CaptureRender>,
+    mut images: ResMut<Assets<Image>>,
+    capture: Option<Res<MountainCapture>>,
+    settings: Res<MountainCaptureSettings>,
+    render_device: Res<RenderDevice>,
+    cameras: Query<(&Camera, &Transform, &Projection), With<PanOrbitCamera>>,
+) {
+    if evr.read().count() == 0 || capture.is_some() {
+        return;
+    }
+
+    let Ok((main_camera, transform, Projection::Perspective(perspective))) = cameras.get_single() else {
+        warn!("Render capture needs a single perspective orbit camera");
+        return;
+    };
+
+    let supersampling = settings.supersampling.max(1);
+    let size = UVec2::new(settings.width, settings.height).max(UVec2::ONE) * supersampling;
+    let max_tile_size = render_device.limits().max_texture_dimension_2d;
+    let tile_count = (size + max_tile_size - 1) / max_tile_size;
+    let format = if settings.exr { TextureFormat::Rgba16Float } else { TextureFormat::Rgba8UnormSrgb };
+
+    let mut tiles = Vec::new();
+    for ty in 0..tile_count.y {
+        for tx in 0..tile_count.x {
+            let offset = UVec2::new(tx, ty) * size / tile_count;
+            let end = (UVec2::new(tx, ty) + 1) * size / tile_count;
+            let image = images.add(create_target(end - offset, format));
+
+            let ndc = |p: UVec2| Vec2::new(p.x as f32 / size.x as f32 * 2.0 - 1.0, 1.0 - p.y as f32 / size.y as f32 * 2.0);
+            let projection = TileProjection {
+                fov: perspective.fov,
+                aspect_ratio: size.x as f32 / size.y as f32,
+                near: perspective.near,
+                far: perspective.far,
+                min: Vec2::new(ndc(offset).x, ndc(end).y),
+                max: Vec2::new(ndc(end).x, ndc(offset).y),
+            };
+
+            let camera = commands.spawn((
+                Camera {
+                    order: -1,
+                    target: RenderTarget::Image(image.clone()),
+                    clear_color: main_camera.clear_color.clone(),
+                    // EXR captures keep the untonemapped scene values.
+                    hdr: settings.exr,
+                    ..default()
+                },
+                CameraRenderGraph::new(Core3d),
+                projection,
+                VisibleEntities::default(),
+                Frustum::default(),
+                *transform,
+                GlobalTransform::from(*transform),
+                Camera3d::default(),
+                if settings.exr { Tonemapping::None } else { Tonemapping::default() },
+                if settings.exr { DebandDither::Disabled } else { DebandDither::Enabled },
+                ColorGrading::default(),
+                Exposure::default(),
+            )).id();
+
+            tiles.push(CaptureTile { camera, image, offset, size: end - offset, readback: None, stitched: false });
+        }
+    }
+
+    info!("Capturing {}x{} render in {} tiles", size.x, size.y, tiles.len());
+    let pixels = vec![[0.0; 4]; (size.x / supersampling * size.y / supersampling) as usize];
+    commands.insert_resource(MountainCapture { tiles, size, supersampling, exr: settings.exr, frames: 0, pixels });
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 }
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as f32;
+
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        31 => if mantissa == 0.0 { sign * f32::INFINITY } else { f32::NAN },
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+impl MountainCapture {
+    // Adds a tile's readback to the output, so only one tile is held at the supersampled size.
+    fn stitch(&mut self, tile: usize, data: &[u8]) {
+        let (offset, size) = (self.tiles[tile].offset, self.tiles[tile].size);
+        let ss = self.supersampling;
+        let width = self.size.x / ss;
+        let weight = 1.0 / (ss * ss) as f32;
+
+        let bytes_per_texel = if self.exr { 8 } else { 4 };
+        for (i, p) in data.chunks_exact(bytes_per_texel).enumerate() {
+            let texel: [f32; 4] = if self.exr {
+                std::array::from_fn(|c| f16_to_f32(u16::from_le_bytes([p[c * 2], p[c * 2 + 1]])))
+            } else {
+                std::array::from_fn(|c| if c < 3 { srgb_to_linear(p[c] as f32 / 255.0) } else { p[c] as f32 / 255.0 })
+            };
+
+            let (x, y) = (offset.x + i as u32 % size.x, offset.y + i as u32 / size.x);
+            let pixel = &mut self.pixels[((y / ss) * width + x / ss) as usize];
+            for c in 0..4 {
+                pixel[c] += texel[c] * weight;
+            }
+        }
+
+        self.tiles[tile].stitched = true;
+    }
+
+    fn save(&mut self) -> Result<PathBuf, String> {
+        std::fs::create_dir_all(CAPTURE_DIR).map_err(|e| e.to_string())?;
+        let size = self.size / self.supersampling;
+        let pixels = std::mem::take(&mut self.pixels);
+
+        if self.exr {
+            let path = next_capture_path("exr");
+            let data = pixels.into_iter().flatten().collect();
+            ImageBuffer::<Rgba<f32>, Vec<f32>>::from_raw(size.x, size.y, data)
+                .unwrap()
+                .save(&path)
+                .map_err(|e| e.to_string())?;
+            Ok(path)
+        } else {
+            let path = next_capture_path("png");
+            let data = pixels.into_iter().flat_map(|p| {
+                [linear_to_srgb(p[0]), linear_to_srgb(p[1]), linear_to_srgb(p[2]), p[3]].map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8)
+            }).collect();
+            ImageBuffer::<Rgba<u8>, Vec<u8>>::from_raw(size.x, size.y, data)
+                .unwrap()
+                .save(&path)
+                .map_err(|e| e.to_string())?;
+            Ok(path)
+        }
+    }
+}
+
+pub fn update_capture(
+    mut commands: Commands,
+    mut evr: EventReader<ReadbackComplete>,
+    mut capture: Option<ResMut<MountainCapture>>,
+    mut readback_requests: ResMut<MountainReadbackRequests>,
+) {
+    let Some(capture) = capture.as_mut() else { return };
+
+    capture.frames += 1;
+    if capture.frames == CAPTURE_WARMUP_FRAMES {
+        for tile in capture.tiles.iter_mut() {
+            tile.readback = Some(readback_requests.request(tile.image.clone()));
+        }
+    }
+
+    for readback in evr.read() {
+        if let Some(tile) = capture.tiles.iter().position(|tile| tile.readback == Some(readback.id)) {
+            capture.stitch(tile, &readback.data);
+        }
+    }
+
+    if capture.tiles.iter().any(|tile| !tile.stitched) {
+        return;
+    }
+
+    for tile in capture.tiles.iter() {
+        commands.entity(tile.camera).despawn();
+    }
+
+    match capture.save() {
+        Ok(path) => info!("Saved render capture to {}", path.display()),
+        Err(e) => error!("Failed to save render capture: {}", e),
+    }
+    commands.remove_resource::<MountainCapture>();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(min: Vec2, max: Vec2) -> TileProjection {
+        TileProjection { aspect_ratio: 2.0, min, max, ..default() }
+    }
+
+    fn capture(size: UVec2, supersampling: u32, tiles: &[(UVec2, UVec2)]) -> MountainCapture {
+        MountainCapture {
+            tiles: tiles.iter().map(|&(offset, size)| CaptureTile {
+                camera: Entity::PLACEHOLDER,
+                image: Handle::default(),
+                offset,
+                size,
+                readback: None,
+                stitched: false,
+            }).collect(),
+            size,
+            supersampling,
+            exr: false,
+            frames: 0,
+            pixels: vec![[0.0; 4]; (size.x / supersampling * size.y / supersampling) as usize],
+        }
+    }
+
+    #[test]
+    fn stitching_box_filters_tiles_into_the_output() {
+        // Two 2x4 tiles side by side, supersampled 2x into a 2x2 output.
+        let mut capture = capture(UVec2::new(4, 4), 2, &[(UVec2::ZERO, UVec2::new(2, 4)), (UVec2::new(2, 0), UVec2::new(2, 4))]);
+
+        let black_and_white = [[0, 0, 0, 255], [255, 255, 255, 255]].repeat(4).concat();
+        let white = [255; 32];
+        capture.stitch(1, &white);
+        capture.stitch(0, &black_and_white);
+
+        assert!(capture.tiles.iter().all(|tile| tile.stitched));
+        assert_eq!(capture.pixels, [[0.5, 0.5, 0.5, 1.0], [1.0; 4], [0.5, 0.5, 0.5, 1.0], [1.0; 4]]);
+    }
+
+    #[test]
+    fn full_tile_matches_perspective() {
+        let projection = tile(Vec2::NEG_ONE, Vec2::ONE);
+        let perspective = Mat4::perspective_infinite_reverse_rh(projection.fov, 2.0, projection.near);
+
+        assert!(projection.get_projection_matrix().abs_diff_eq(perspective, 1e-6));
+    }
+
+    #[test]
+    fn tile_covers_its_part_of_the_view() {
+        let full = tile(Vec2::NEG_ONE, Vec2::ONE).get_projection_matrix();
+        let quarter = tile(Vec2::ZERO, Vec2::ONE).get_projection_matrix();
+
+        for (ndc, expected) in [(Vec2::ZERO, Vec2::NEG_ONE), (Vec2::splat(0.5), Vec2::ZERO), (Vec2::ONE, Vec2::ONE)] {
+            let view = full.inverse().project_point3(ndc.extend(0.01));
+            let projected = quarter.project_point3(view);
+
+            assert!(projected.truncate().abs_diff_eq(expected, 1e-4), "{} -> {}", ndc, projected);
+            assert!((projected.z - 0.01).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn full_tile_frustum_matches_perspective() {
+        let projection = tile(Vec2::NEG_ONE, Vec2::ONE);
+        let perspective = PerspectiveProjection { aspect_ratio: 2.0, ..default() };
+
+        let corners = projection.get_frustum_corners(-1.0, -10.0);
+        for (corner, expected) in corners.iter().zip(perspective.get_frustum_corners(-1.0, -10.0)) {
+            assert!(corner.abs_diff_eq(expected, 1e-5), "{} != {}", corner, expected);
+        }
+    }
+
+    #[test]
+    fn tile_frustum_is_a_window_of_the_full_one() {
+        let projection = tile(Vec2::ZERO, Vec2::ONE);
+        let tan_half_fov = (projection.fov / 2.0).tan();
+
+        let corners = projection.get_frustum_corners(-1.0, -10.0);
+        assert!(corners[1].abs_diff_eq(Vec3A::new(2.0 * tan_half_fov, tan_half_fov, -1.0), 1e-5));
+        assert!(corners[3].abs_diff_eq(Vec3A::new(0.0, 0.0, -1.0), 1e-5));
+        assert!(corners[5].abs_diff_eq(Vec3A::new(20.0 * tan_half_fov, 10.0 * tan_half_fov, -10.0), 1e-4));
+    }
+}
@@ -0,0 +1,125 @@
+use std::{fs::File, io::{BufWriter, Write}, path::Path};
+
+use bevy::math::DVec2;
+use tiff::{encoder::{colortype::Gray32Float, TiffEncoder}, tags::Tag};
+
+// GTModelType = projected, GTRasterType = pixel is area, user defined projection,
+// linear and vertical units in meters.
+const GEO_KEY_DIRECTORY: [u16; 24] = [
+    1, 1, 0, 5,
+    1024, 0, 1, 1,
+    1025, 0, 1, 1,
+    3072, 0, 1, 32767,
+    3076, 0, 1, 9001,
+    4099, 0, 1, 9001,
+];
+
+pub struct GeoGrid<'a> {
+    pub elevations: &'a [f32],
+    pub size: u32,
+    // Pixel is area, each elevation covers a whole cell, matching the DEM import.
+    pub cell_size: f64,
+    // Lower left corner of the grid, rows are written north to south.
+    pub origin: DVec2,
+}
+
+impl<'a> GeoGrid<'a> {
+    fn top(&self) -> f64 {
+        self.origin.y + self.cell_size * self.size as f64
+    }
+}
+
+pub fn write_geotiff(path: impl AsRef<Path>, grid: &GeoGrid) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| e.to_string())?;
+    let mut encoder = TiffEncoder::new(BufWriter::new(file)).map_err(|e| e.to_string())?;
+    let mut image = encoder.new_image::<Gray32Float>(grid.size, grid.size).map_err(|e| e.to_string())?;
+
+    let tags = image.encoder();
+    tags.write_tag(Tag::ModelPixelScaleTag, &[grid.cell_size, grid.cell_size, 0.0][..]).map_err(|e| e.to_string())?;
+    tags.write_tag(Tag::ModelTiepointTag, &[0.0, 0.0, 0.0, grid.origin.x, grid.top(), 0.0][..]).map_err(|e| e.to_string())?;
+    tags.write_tag(Tag::GeoKeyDirectoryTag, &GEO_KEY_DIRECTORY[..]).map_err(|e| e.to_string())?;
+
+    image.write_data(grid.elevations).map_err(|e| e.to_string())
+}
+
+pub fn write_ascii_grid(path: impl AsRef<Path>, grid: &GeoGrid) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| e.to_string())?;
+    let mut out = BufWriter::new(file);
+
+    write!(
+        out,
+        "ncols {}\nnrows {}\nxllcorner {}\nyllcorner {}\ncellsize {}\n",
+        grid.size, grid.size, grid.origin.x, grid.origin.y, grid.cell_size,
+    ).map_err(|e| e.to_string())?;
+
+    for row in grid.elevations.chunks_exact(grid.size as usize) {
+        let line = row.iter().map(|e| format!("{:.3}", e)).collect::<Vec<_>>().join(" ");
+        writeln!(out, "{}", line).map_err(|e| e.to_string())?;
+    }
+
+    out.flush().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use tiff::decoder::{Decoder, DecodingResult};
+
+    use crate::import::DemGrid;
+
+    use super::*;
+
+    const ELEVATIONS: [f32; 4] = [10.0, 20.5, -3.25, 0.0];
+
+    fn grid() -> GeoGrid<'static> {
+        GeoGrid {
+            elevations: &ELEVATIONS,
+            size: 2,
+            cell_size: 30.0,
+            origin: DVec2::new(1000.0, 2000.0),
+        }
+    }
+
+    // Unique to the test and the process, so concurrent runs don't write over each other.
+    fn test_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("mountain_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn geo_key_directory_counts_its_keys() {
+        assert_eq!(GEO_KEY_DIRECTORY[3] as usize, GEO_KEY_DIRECTORY.len() / 4 - 1);
+        assert_eq!(GEO_KEY_DIRECTORY.len() % 4, 0);
+    }
+
+    #[test]
+    fn geotiff_is_georeferenced_from_the_top_left_corner() {
+        let path = test_path("height.tif");
+        write_geotiff(&path, &grid()).unwrap();
+
+        let mut decoder = Decoder::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (2, 2));
+        assert_eq!(decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag).unwrap(), [30.0, 30.0, 0.0]);
+        assert_eq!(decoder.get_tag_f64_vec(Tag::ModelTiepointTag).unwrap(), [0.0, 0.0, 0.0, 1000.0, 2060.0, 0.0]);
+        assert_eq!(decoder.get_tag_u16_vec(Tag::GeoKeyDirectoryTag).unwrap(), GEO_KEY_DIRECTORY);
+
+        let DecodingResult::F32(data) = decoder.read_image().unwrap() else { panic!("expected a float band") };
+        assert_eq!(data, ELEVATIONS);
+
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn ascii_grid_imports_back() {
+        let path = test_path("height.asc");
+        write_ascii_grid(&path, &grid()).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("ncols 2\nnrows 2\nxllcorner 1000\nyllcorner 2000\ncellsize 30\n"));
+
+        let dem = DemGrid::from_ascii_grid(&contents).unwrap();
+        assert_eq!(dem.size, 2);
+        assert_eq!(dem.cell_size, 30.0);
+        assert_eq!(dem.elevations, ELEVATIONS);
+
+        std::fs::remove_file(path).ok();
+    }
+}
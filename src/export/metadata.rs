@@ -0,0 +1,177 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::EROSION_MASK_FILE;
+use crate::{
+    compute::{
+        node::MountainComputeReady,
+        uniforms::{ErosionFinished, MountainComputeSettings, MountainComputeTextures, MountainErosionTrigger},
+        TEXTURE_SIZE,
+    },
+    import::load_erosion_mask,
+    material::MountainMaterial,
+    terrain::{MountainTerrain, MountainTerrainSettings},
+};
+
+#[derive(Serialize, Deserialize)]
+pub struct MountainExportMetadata {
+    pub settings: MountainComputeSettings,
+    pub resolution: u32,
+    pub plane_length: f32,
+    pub terrain_height: f32,
+    // Eroded droplets since generation.
+    pub droplets: u64,
+    // Of every channel of the exported map, not just the heights.
+    pub content_hash: String,
+}
+
+impl MountainExportMetadata {
+    pub fn new(settings: MountainComputeSettings, terrain_height: f32, plane_length: f32, droplets: u64) -> Self {
+        Self {
+            settings,
+            resolution: TEXTURE_SIZE,
+            plane_length,
+            terrain_height,
+            droplets,
+            content_hash: String::new(),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        ron::from_str(&contents).map_err(|e| e.to_string())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let contents = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(|e| e.to_string())?;
+        fs::write(path, contents).map_err(|e| e.to_string())
+    }
+}
+
+// FNV-1a over every channel of the map, so hashes stay comparable between runs and builds.
+pub fn content_hash(data: &[u8]) -> String {
+    let mut hash = 0xcbf29ce484222325u64;
+
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    format!("{:016x}", hash)
+}
+
+#[derive(Resource)]
+pub struct MountainReplay {
+    pub droplets: u64,
+    pub terrain_height: f32,
+    // Exported next to the sidecar when erosion was masked.
+    pub erosion_mask: Option<PathBuf>,
+    started: bool,
+}
+
+pub fn load_replay(
+    mut commands: Commands,
+    mut settings: ResMut<MountainComputeSettings>,
+    mut terrain_settings: ResMut<MountainTerrainSettings>,
+) {
+    let Some(path) = crate::launch_arg("--from") else { return };
+
+    let metadata = match MountainExportMetadata::load(&path) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            error!("Failed to load sidecar {}: {}", path, e);
+            return;
+        }
+    };
+
+    if metadata.resolution != TEXTURE_SIZE {
+        warn!("Sidecar {} was exported at a different resolution", path);
+    }
+
+    let erosion_mask = metadata.settings.masked_erosion
+        .then(|| Path::new(&path).with_file_name(EROSION_MASK_FILE));
+
+    *settings = metadata.settings;
+    terrain_settings.world_size = metadata.plane_length;
+    commands.insert_resource(MountainReplay {
+        droplets: metadata.droplets,
+        terrain_height: metadata.terrain_height,
+        erosion_mask,
+        started: false,
+    });
+    settings.iteration = 0;
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn replay_metadata(
+    mut commands: Commands,
+    replay: Option<ResMut<MountainReplay>>,
+    ready: Res<MountainComputeReady>,
+    mut settings: ResMut<MountainComputeSettings>,
+    mut images: ResMut<Assets<Image>>,
+    compute_textures: Res<MountainComputeTextures>,
+    mut erosion_evw: EventWriter<MountainErosionTrigger>,
+    mut finished_evr: EventReader<ErosionFinished>,
+    terrain: Res<MountainTerrain>,
+    mut materials: ResMut<Assets<MountainMaterial>>,
+) {
+    let Some(mut replay) = replay else { return };
+
+    if !ready.get() {
+        return;
+    }
+
+    if !replay.started {
+        materials.get_mut(&terrain.material).unwrap().settings.terrain_height = replay.terrain_height;
+
+        // Without its mask a masked run rejects every droplet.
+        if let Some(path) = replay.erosion_mask.as_ref() {
+            if let Err(e) = load_erosion_mask(path, &mut images, &compute_textures) {
+                warn!("Replaying without erosion mask, failed to load {}: {}", path.display(), e);
+                settings.masked_erosion = false;
+            }
+        }
+
+        if replay.droplets > 0 {
+            erosion_evw.send(MountainErosionTrigger::Run { droplets: replay.droplets });
+        } else {
+            commands.remove_resource::<MountainReplay>();
+        }
+
+        replay.started = true;
+        return;
+    }
+
+    if let Some(finished) = finished_evr.read().last() {
+        commands.remove_resource::<MountainReplay>();
+        info!("Replayed {} erosion droplets", finished.droplets);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_hash_matches_fnv1a() {
+        assert_eq!(content_hash(b""), "cbf29ce484222325");
+        assert_eq!(content_hash(b"a"), "af63dc4c8601ec8c");
+        assert_eq!(content_hash(b"foobar"), "85944171f73967e8");
+    }
+
+    #[test]
+    fn content_hash_covers_every_channel() {
+        let texels = [0.25f32, 0.5, 0.75, 1.0].repeat(4);
+        let data: Vec<u8> = texels.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let hash = content_hash(&data);
+
+        for channel in 0..4 {
+            let mut changed = data.clone();
+            changed[channel * 4] ^= 1;
+            assert_ne!(content_hash(&changed), hash, "channel {}", channel);
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use std::{path::PathBuf, sync::{mpsc::channel, Mutex}};
+
+use bevy::{math::DVec2, prelude::*, render::{camera::CameraProjectionPlugin, extract_resource::ExtractResourcePlugin, Render, RenderApp, RenderSet}};
+use bevy_image_export::{ImageExportBundle, ImageExportSettings, ImageExportSource};
+use capture::{start_capture, update_capture, CaptureRender, MountainCaptureSettings, TileProjection};
+use gis::{write_ascii_grid, write_geotiff, GeoGrid};
+use image::{ImageBuffer, Luma};
+use metadata::{content_hash, load_replay, replay_metadata, MountainExportMetadata};
+use readback::{
+    clear_readback_requests, readback_images, receive_readbacks, MountainPendingReadbacks, MountainReadbackReceiver,
+    MountainReadbackRequests, MountainReadbackSender, ReadbackComplete,
+};
+use tiles::write_tiles;
+
+use crate::{
+    compute::{
+        stage::{schedule_compute_stages, MountainComputeQueue, PREPARE_WRITE_STAGE},
+        uniforms::{update_erosion_iteration, update_erosion_status, MountainComputeSettings, MountainComputeTextures, MountainErosionProgress},
+    },
+    material::MountainMaterial,
+    terrain::{MountainTerrain, MountainTerrainSettings},
+};
+
+pub mod capture;
+pub mod gis;
+pub mod metadata;
+pub mod readback;
+pub mod tiles;
+
+pub const EXPORT_DIR: &str = "heightmaps";
+// Written next to the metadata when erosion was masked, so replays can load it back.
+pub const EROSION_MASK_FILE: &str = "erosion_mask.png";
+
+#[derive(Resource, Reflect, Clone)]
+#[reflect(Resource)]
+pub struct MountainExportSettings {
+    pub geotiff: bool,
+    pub ascii_grid: bool,
+    // Full resolution sky visibility map next to the heightmap.
+    pub ao: bool,
+    pub origin: DVec2,
+    pub tiles_per_side: u32,
+    pub tile_size: u32,
+}
+
+impl Default for MountainExportSettings {
+    fn default() -> Self {
+        Self {
+            geotiff: true,
+            ascii_grid: false,
+            ao: true,
+            origin: DVec2::ZERO,
+            tiles_per_side: 0,
+            tile_size: 505,
+        }
+    }
+}
+
+#[derive(Event)]
+pub struct ExportHeightmap;
+
+struct PendingExport {
+    // Of the export texture, which the heights, hash and GIS rasters come from.
+    readback: Option<u32>,
+    // Of the heightmap, for the tiles and occlusion map, which need channels the export texture doesn't keep.
+    map_readback: Option<u32>,
+    wants_map: bool,
+    mask_readback: Option<u32>,
+    dir: PathBuf,
+    metadata: MountainExportMetadata,
+}
+
+#[derive(Resource, Default)]
+pub struct MountainPendingExports(Vec<PendingExport>);
+
+fn next_export_dir() -> PathBuf {
+    (0..)
+        .map(|i| PathBuf::from(EXPORT_DIR).join(format!("export_{:03}", i)))
+        .find(|dir| !dir.exists())
+        .unwrap()
+}
+
+// Runs after the erosion batches for the frame are counted and before the stages are scheduled,
+// so the write pass, the readback and the metadata all describe the same heightmap.
+#[allow(clippy::too_many_arguments)]
+fn export_heightmap(
+    mut commands: Commands,
+    mut evr: EventReader<ExportHeightmap>,
+    mut queue: ResMut<MountainComputeQueue>,
+    mut exr_dir: Local<Option<PathBuf>>,
+    mut export_sources: ResMut<Assets<ImageExportSource>>,
+    mut readback_requests: ResMut<MountainReadbackRequests>,
+    mut pending: ResMut<MountainPendingExports>,
+    export_settings: Res<MountainExportSettings>,
+    compute_textures: Res<MountainComputeTextures>,
+    compute_settings: Res<MountainComputeSettings>,
+    erosion_progress: Res<MountainErosionProgress>,
+    terrain_settings: Res<MountainTerrainSettings>,
+    image_exports: Query<Entity, With<ImageExportSettings>>,
+    terrain: Res<MountainTerrain>,
+    materials: Res<Assets<MountainMaterial>>,
+) {
+    for entity in image_exports.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    // The export texture is written once the frame's passes run, the EXR is copied the frame after.
+    if let Some(dir) = exr_dir.take() {
+        commands.spawn(ImageExportBundle {
+            source: export_sources.add(compute_textures.export.clone()),
+            settings: ImageExportSettings {
+                output_dir: dir.to_string_lossy().into(),
+                extension: "exr".into(),
+            }
+        });
+    }
+
+    if evr.read().count() == 0 {
+        return;
+    }
+
+    let dir = next_export_dir();
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        error!("Failed to create export directory {}: {}", dir.display(), e);
+        return;
+    }
+
+    queue.request(PREPARE_WRITE_STAGE);
+
+    let terrain_height = materials.get(&terrain.material)
+        .map_or(0.0, |mat| mat.settings.terrain_height);
+
+    pending.0.push(PendingExport {
+        readback: Some(readback_requests.request(compute_textures.export.clone())),
+        map_readback: None,
+        wants_map: export_settings.ao || export_settings.tiles_per_side > 0,
+        mask_readback: compute_settings.masked_erosion
+            .then(|| readback_requests.request(compute_textures.erosion_mask.clone())),
+        dir: dir.clone(),
+        metadata: MountainExportMetadata::new(compute_settings.clone(), terrain_height, terrain_settings.world_size, erosion_progress.total),
+    });
+    *exr_dir = Some(dir);
+}
+
+// `map` is only current once the stages are scheduled.
+fn request_export_map(
+    mut pending: ResMut<MountainPendingExports>,
+    mut readback_requests: ResMut<MountainReadbackRequests>,
+    compute_textures: Res<MountainComputeTextures>,
+) {
+    for export in pending.0.iter_mut().filter(|export| export.wants_map && export.map_readback.is_none()) {
+        export.map_readback = Some(readback_requests.request(compute_textures.map().clone()));
+    }
+}
+
+fn write_exports(
+    mut evr: EventReader<ReadbackComplete>,
+    mut pending: ResMut<MountainPendingExports>,
+    export_settings: Res<MountainExportSettings>,
+) {
+    for readback in evr.read() {
+        if let Some(export) = pending.0.iter_mut().find(|export| export.mask_readback == Some(readback.id)) {
+            export.mask_readback = None;
+
+            let path = export.dir.join(EROSION_MASK_FILE);
+            let pixels = readback.data
+                .chunks_exact(4)
+                .map(|texel| (f32::from_le_bytes(texel.try_into().unwrap()).clamp(0.0, 1.0) * u16::MAX as f32) as u16)
+                .collect();
+            if let Err(e) = ImageBuffer::<Luma<u16>, Vec<u16>>::from_raw(readback.size.x, readback.size.y, pixels).unwrap().save(&path) {
+                error!("Failed to write erosion mask to {}: {}", path.display(), e);
+            }
+        }
+
+        if let Some(export) = pending.0.iter_mut().find(|export| export.map_readback == Some(readback.id)) {
+            export.wants_map = false;
+
+            if export_settings.tiles_per_side > 0 && export_settings.tile_size > 1 {
+                let path = export.dir.join("tiles");
+                if let Err(e) = write_tiles(
+                    &path,
+                    readback,
+                    export_settings.tiles_per_side,
+                    export_settings.tile_size,
+                    export.metadata.plane_length,
+                    export.metadata.terrain_height,
+                ) {
+                    error!("Failed to write tiles to {}: {}", path.display(), e);
+                }
+            }
+
+            if export_settings.ao {
+                let path = export.dir.join("ao.png");
+                let pixels = readback.channel(2).iter().map(|ao| (ao.clamp(0.0, 1.0) * u16::MAX as f32) as u16).collect();
+                if let Err(e) = ImageBuffer::<Luma<u16>, Vec<u16>>::from_raw(readback.size.x, readback.size.y, pixels).unwrap().save(&path) {
+                    error!("Failed to write ambient occlusion map to {}: {}", path.display(), e);
+                }
+            }
+        }
+
+        let Some(export) = pending.0.iter_mut().find(|export| export.readback == Some(readback.id)) else { continue };
+        export.readback = None;
+        let heights = readback.channel(0);
+
+        export.metadata.resolution = readback.size.x;
+        export.metadata.content_hash = content_hash(&readback.data);
+
+        let path = export.dir.join("metadata.ron");
+        match export.metadata.save(&path) {
+            Ok(()) => info!("Wrote export metadata to {}", path.display()),
+            Err(e) => error!("Failed to write export metadata to {}: {}", path.display(), e),
+        }
+
+        if !export_settings.geotiff && !export_settings.ascii_grid {
+            continue;
+        }
+
+        let elevations = heights.iter().map(|h| h * export.metadata.terrain_height).collect::<Vec<_>>();
+        let grid = GeoGrid {
+            elevations: &elevations,
+            size: readback.size.x,
+            cell_size: export.metadata.plane_length as f64 / readback.size.x as f64,
+            origin: export_settings.origin,
+        };
+
+        if export_settings.geotiff {
+            let path = export.dir.join("height.tif");
+            if let Err(e) = write_geotiff(&path, &grid) {
+                error!("Failed to write GeoTIFF to {}: {}", path.display(), e);
+            }
+        }
+
+        if export_settings.ascii_grid {
+            let path = export.dir.join("height.asc");
+            if let Err(e) = write_ascii_grid(&path, &grid) {
+                error!("Failed to write ASCII grid to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    pending.0.retain(|export| export.readback.is_some() || export.wants_map || export.mask_readback.is_some());
+}
+
+pub struct MountainExportPlugin;
+
+impl Plugin for MountainExportPlugin {
+    fn build(&self, app: &mut App) {
+        let (sender, receiver) = channel();
+
+        app
+            .insert_resource(MountainReadbackReceiver(Mutex::new(receiver)))
+            .init_resource::<MountainReadbackRequests>()
+            .init_resource::<MountainExportSettings>()
+            .init_resource::<MountainPendingExports>()
+            .init_resource::<MountainCaptureSettings>()
+            .add_event::<ExportHeightmap>()
+            .add_event::<CaptureRender>()
+            .add_event::<ReadbackComplete>()
+            .add_systems(Startup, load_replay)
+            .add_systems(First, clear_readback_requests)
+            .add_systems(PreUpdate, receive_readbacks)
+            .add_systems(Update, (write_exports, replay_metadata.before(update_erosion_status)))
+            .add_systems(PostUpdate, (
+                export_heightmap.after(update_erosion_iteration).before(schedule_compute_stages),
+                request_export_map.after(schedule_compute_stages),
+            ))
+            .add_systems(Update, (start_capture, update_capture).chain())
+            .add_plugins((
+                ExtractResourcePlugin::<MountainReadbackRequests>::default(),
+                CameraProjectionPlugin::<TileProjection>::default(),
+            ));
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .insert_resource(MountainReadbackSender(Mutex::new(sender)))
+            .init_resource::<MountainPendingReadbacks>()
+            .add_systems(Render, readback_images.in_set(RenderSet::Cleanup));
+    }
+}
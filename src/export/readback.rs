@@ -0,0 +1,172 @@
+use std::sync::{mpsc::{Receiver, Sender}, Arc, Mutex};
+
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::ExtractResource,
+        render_asset::RenderAssets,
+        render_resource::{
+            Buffer, BufferDescriptor, BufferUsages, CommandEncoderDescriptor, Extent3d, ImageCopyBuffer,
+            ImageDataLayout, Maintain, MapMode,
+        },
+        renderer::{RenderDevice, RenderQueue},
+    },
+};
+
+#[derive(Clone)]
+pub struct ReadbackRequest {
+    pub id: u32,
+    pub image: Handle<Image>,
+}
+
+#[derive(Resource, ExtractResource, Default, Clone)]
+pub struct MountainReadbackRequests {
+    next_id: u32,
+    requests: Vec<ReadbackRequest>,
+}
+
+impl MountainReadbackRequests {
+    pub fn request(&mut self, image: Handle<Image>) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.requests.push(ReadbackRequest { id, image });
+        id
+    }
+}
+
+#[derive(Event)]
+pub struct ReadbackComplete {
+    pub id: u32,
+    pub size: UVec2,
+    pub data: Vec<u8>,
+}
+
+impl ReadbackComplete {
+    pub fn channel(&self, channel: usize) -> Vec<f32> {
+        self.data
+            .chunks_exact(16)
+            .map(|texel| f32::from_le_bytes(texel[channel * 4..channel * 4 + 4].try_into().unwrap()))
+            .collect()
+    }
+}
+
+#[derive(Resource)]
+pub struct MountainReadbackSender(pub Mutex<Sender<ReadbackComplete>>);
+
+#[derive(Resource)]
+pub struct MountainReadbackReceiver(pub Mutex<Receiver<ReadbackComplete>>);
+
+pub fn clear_readback_requests(mut requests: ResMut<MountainReadbackRequests>) {
+    if !requests.requests.is_empty() {
+        requests.requests.clear();
+    }
+}
+
+pub fn receive_readbacks(
+    receiver: Res<MountainReadbackReceiver>,
+    mut evw: EventWriter<ReadbackComplete>,
+) {
+    let receiver = receiver.0.lock().unwrap();
+    while let Ok(readback) = receiver.try_recv() {
+        evw.send(readback);
+    }
+}
+
+struct PendingReadback {
+    id: u32,
+    size: UVec2,
+    row_bytes: usize,
+    padded_row_bytes: usize,
+    buffer: Buffer,
+    // Set by the map callback, to whether mapping succeeded.
+    mapped: Arc<Mutex<Option<bool>>>,
+}
+
+// Copies waiting for their staging buffer to map, the render schedule never blocks on them.
+#[derive(Resource, Default)]
+pub struct MountainPendingReadbacks(Vec<PendingReadback>);
+
+pub fn readback_images(
+    mut requests: ResMut<MountainReadbackRequests>,
+    mut pending: ResMut<MountainPendingReadbacks>,
+    sender: Res<MountainReadbackSender>,
+    gpu_images: Res<RenderAssets<Image>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    for request in std::mem::take(&mut requests.requests) {
+        let Some(image) = gpu_images.get(&request.image) else {
+            warn!("Skipping readback of an image that is not on the GPU yet");
+            continue;
+        };
+
+        let size = image.size.as_uvec2();
+        let row_bytes = size.x as usize * image.texture_format.block_copy_size(None).unwrap() as usize;
+        let padded_row_bytes = RenderDevice::align_copy_bytes_per_row(row_bytes);
+
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("mountain_readback_buffer"),
+            size: (padded_row_bytes * size.y as usize) as u64,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("mountain_readback_encoder"),
+        });
+
+        encoder.copy_texture_to_buffer(
+            image.texture.as_image_copy(),
+            ImageCopyBuffer {
+                buffer: &buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_row_bytes as u32),
+                    rows_per_image: None,
+                },
+            },
+            Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+        );
+
+        render_queue.submit([encoder.finish()]);
+
+        let mapped = Arc::new(Mutex::new(None));
+        let callback_mapped = mapped.clone();
+        buffer.slice(..).map_async(MapMode::Read, move |result| {
+            *callback_mapped.lock().unwrap() = Some(result.is_ok());
+        });
+
+        pending.0.push(PendingReadback { id: request.id, size, row_bytes, padded_row_bytes, buffer, mapped });
+    }
+
+    if pending.0.is_empty() {
+        return;
+    }
+
+    render_device.poll(Maintain::Poll);
+
+    pending.0.retain(|readback| {
+        let Some(ok) = *readback.mapped.lock().unwrap() else { return true };
+        if !ok {
+            error!("Failed to map readback buffer");
+            return false;
+        }
+
+        let mut data = Vec::with_capacity(readback.row_bytes * readback.size.y as usize);
+        for row in readback.buffer.slice(..).get_mapped_range().chunks_exact(readback.padded_row_bytes) {
+            data.extend_from_slice(&row[..readback.row_bytes]);
+        }
+        readback.buffer.unmap();
+
+        let _ = sender.0.lock().unwrap().send(ReadbackComplete {
+            id: readback.id,
+            size: readback.size,
+            data,
+        });
+        false
+    });
+}
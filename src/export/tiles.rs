@@ -0,0 +1,222 @@
+use std::{fs, path::Path};
+
+use image::{ImageBuffer, Luma};
+use serde::{Deserialize, Serialize};
+
+use super::readback::ReadbackComplete;
+use crate::compute::TEXTURE_SIZE;
+
+// Channels of `map` written next to the height tiles.
+pub const MASK_CHANNELS: [(&str, usize); 2] = [("shadow", 1), ("ao", 2)];
+// Side of the resampled grid all tiles are cut from, every channel of it is held in memory at once.
+pub const MAX_TILE_RESOLUTION: u32 = TEXTURE_SIZE * 2;
+
+#[derive(Serialize, Deserialize)]
+pub struct TileEntry {
+    pub x: u32,
+    pub y: u32,
+    pub pixel_offset: [u32; 2],
+    // Outer corner of the tile's first pixel, pixels are areas like in the GIS rasters.
+    pub world_offset: [f32; 2],
+    pub height: String,
+    pub masks: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TileManifest {
+    pub tiles_per_side: u32,
+    pub tile_size: u32,
+    pub resolution: u32,
+    pub world_size: f32,
+    // Covered by each tile, neighbours overlap by one pixel.
+    pub tile_world_size: f32,
+    pub terrain_height: f32,
+    pub tiles: Vec<TileEntry>,
+}
+
+// Pixel is area, like the GIS rasters and the DEM import, so each pixel samples the map at its centre.
+fn resample(channel: &[f32], size: u32, resolution: u32) -> Vec<f32> {
+    let max = (size - 1) as f32;
+    let scale = size as f32 / resolution as f32;
+    let at = |x: u32, y: u32| channel[(y * size + x) as usize];
+    let source = |i: u32| ((i as f32 + 0.5) * scale - 0.5).clamp(0.0, max);
+
+    (0..resolution * resolution).map(|i| {
+        let (x, y) = (source(i % resolution), source(i / resolution));
+        let (x0, y0) = (x as u32, y as u32);
+        let (x1, y1) = ((x0 + 1).min(size - 1), (y0 + 1).min(size - 1));
+        let (fx, fy) = (x.fract(), y.fract());
+
+        let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+        let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }).collect()
+}
+
+fn crop(channel: &[f32], resolution: u32, offset: [u32; 2], tile_size: u32) -> impl Iterator<Item = f32> + '_ {
+    (0..tile_size * tile_size).map(move |i| {
+        let (x, y) = (offset[0] + i % tile_size, offset[1] + i / tile_size);
+        channel[(y * resolution + x) as usize].clamp(0.0, 1.0)
+    })
+}
+
+pub fn write_tiles(
+    dir: impl AsRef<Path>,
+    readback: &ReadbackComplete,
+    tiles_per_side: u32,
+    tile_size: u32,
+    world_size: f32,
+    terrain_height: f32,
+) -> Result<(), String> {
+    if tiles_per_side == 0 || tile_size < 2 {
+        return Err("Tiles need at least two pixels per side".into());
+    }
+
+    // Neighbouring tiles share their border row and column.
+    let resolution = tiles_per_side as u64 * (tile_size - 1) as u64 + 1;
+    if resolution > MAX_TILE_RESOLUTION as u64 {
+        return Err(format!("{} tiles of {} pixels exceed {} pixels per side", tiles_per_side, tile_size, MAX_TILE_RESOLUTION));
+    }
+    let resolution = resolution as u32;
+    let pixel_world_size = world_size / resolution as f32;
+    let tile_world_size = pixel_world_size * tile_size as f32;
+
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+
+    let height = resample(&readback.channel(0), readback.size.x, resolution);
+    let masks = MASK_CHANNELS
+        .map(|(name, channel)| (name, resample(&readback.channel(channel), readback.size.x, resolution)));
+
+    let mut manifest = TileManifest {
+        tiles_per_side,
+        tile_size,
+        resolution,
+        world_size,
+        tile_world_size,
+        terrain_height,
+        tiles: Vec::new(),
+    };
+
+    for y in 0..tiles_per_side {
+        for x in 0..tiles_per_side {
+            let pixel_offset = [x * (tile_size - 1), y * (tile_size - 1)];
+            let name = format!("tile_x{}_y{}", x, y);
+
+            let height_file = format!("{}_height.png", name);
+            let pixels = crop(&height, resolution, pixel_offset, tile_size).map(|h| (h * u16::MAX as f32) as u16).collect();
+            ImageBuffer::<Luma<u16>, Vec<u16>>::from_raw(tile_size, tile_size, pixels)
+                .unwrap()
+                .save(dir.join(&height_file))
+                .map_err(|e| e.to_string())?;
+
+            let mut mask_files = Vec::new();
+            for (mask, channel) in masks.iter() {
+                let mask_file = format!("{}_{}.png", name, mask);
+                let pixels = crop(channel, resolution, pixel_offset, tile_size).map(|m| (m * u8::MAX as f32) as u8).collect();
+                ImageBuffer::<Luma<u8>, Vec<u8>>::from_raw(tile_size, tile_size, pixels)
+                    .unwrap()
+                    .save(dir.join(&mask_file))
+                    .map_err(|e| e.to_string())?;
+                mask_files.push(mask_file);
+            }
+
+            manifest.tiles.push(TileEntry {
+                x,
+                y,
+                pixel_offset,
+                world_offset: [
+                    -world_size * 0.5 + pixel_offset[0] as f32 * pixel_world_size,
+                    -world_size * 0.5 + pixel_offset[1] as f32 * pixel_world_size,
+                ],
+                height: height_file,
+                masks: mask_files,
+            });
+        }
+    }
+
+    let contents = ron::ser::to_string_pretty(&manifest, ron::ser::PrettyConfig::default()).map_err(|e| e.to_string())?;
+    fs::write(dir.join("manifest.ron"), contents).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::UVec2;
+
+    use super::*;
+
+    fn readback(size: u32, height: impl Fn(u32, u32) -> f32) -> ReadbackComplete {
+        let data = (0..size * size)
+            .flat_map(|i| [height(i % size, i / size), 0.5, 0.25, 0.0])
+            .flat_map(f32::to_le_bytes)
+            .collect();
+
+        ReadbackComplete { id: 0, size: UVec2::splat(size), data }
+    }
+
+    // Unique to the test and the process, so concurrent runs don't write over each other.
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("mountain_{}_{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn resample_keeps_same_resolution() {
+        let channel = [0.0, 0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8];
+        assert_eq!(resample(&channel, 3, 3), channel);
+    }
+
+    #[test]
+    fn resample_samples_pixel_centres() {
+        let upsampled = resample(&[0.0, 1.0, 2.0, 3.0], 2, 4);
+        assert_eq!(upsampled[..4], [0.0, 0.25, 0.75, 1.0]);
+        assert_eq!(upsampled[12..], [2.0, 2.25, 2.75, 3.0]);
+
+        let downsampled = resample(&[0.0, 1.0, 2.0, 3.0].repeat(4), 4, 2);
+        assert_eq!(downsampled[..2], [0.5, 2.5]);
+    }
+
+    #[test]
+    fn crop_clamps_to_unit_range() {
+        let channel = [-1.0, 0.5, 2.0, 0.25];
+        assert_eq!(crop(&channel, 2, [0, 0], 2).collect::<Vec<_>>(), [0.0, 0.5, 1.0, 0.25]);
+        assert_eq!(crop(&channel, 2, [1, 1], 1).collect::<Vec<_>>(), [0.25]);
+    }
+
+    #[test]
+    fn neighbouring_tiles_share_borders() {
+        let dir = test_dir("shared_borders");
+
+        let readback = readback(16, |x, y| (x + y * 16) as f32 / 255.0);
+        write_tiles(&dir, &readback, 2, 5, 100.0, 10.0).unwrap();
+
+        let manifest: TileManifest = ron::from_str(&fs::read_to_string(dir.join("manifest.ron")).unwrap()).unwrap();
+        assert_eq!(manifest.resolution, 9);
+        assert_eq!(manifest.tiles.len(), 4);
+        assert!((manifest.tile_world_size - 500.0 / 9.0).abs() < 1e-4);
+        assert_eq!(manifest.tiles[1].pixel_offset, [4, 0]);
+        assert!((manifest.tiles[1].world_offset[0] - (400.0 / 9.0 - 50.0)).abs() < 1e-4);
+        assert_eq!(manifest.tiles[1].world_offset[1], -50.0);
+        assert_eq!(manifest.tiles[1].masks, ["tile_x1_y0_shadow.png", "tile_x1_y0_ao.png"]);
+
+        let load = |name: &str| image::open(dir.join(name)).unwrap().into_luma16();
+        let top_left = load("tile_x0_y0_height.png");
+        let top_right = load("tile_x1_y0_height.png");
+        let bottom_left = load("tile_x0_y1_height.png");
+        for i in 0..5 {
+            assert_eq!(top_left.get_pixel(4, i), top_right.get_pixel(0, i));
+            assert_eq!(top_left.get_pixel(i, 4), bottom_left.get_pixel(i, 0));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_oversized_tile_grids() {
+        let readback = readback(2, |_, _| 0.0);
+        let dir = test_dir("oversized_tiles");
+
+        assert!(write_tiles(&dir, &readback, 64, MAX_TILE_RESOLUTION, 100.0, 10.0).is_err());
+        assert!(write_tiles(&dir, &readback, u32::MAX, u32::MAX, 100.0, 10.0).is_err());
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,424 @@
+use std::{fs, path::Path};
+
+use bevy::prelude::*;
+
+use crate::{
+    compute::{
+        stage::{MountainComputeQueue, FBM_STAGE},
+        uniforms::{MountainComputeSettings, MountainComputeTextures, MountainHeightChanged},
+        MASK_SIZE, TEXTURE_SIZE,
+    },
+    material::MountainMaterial,
+    terrain::{MountainTerrain, MountainTerrainSettings},
+};
+
+const SRTM_VOID: i16 = -32768;
+// Length of one arc second of latitude.
+const ARC_SECOND_METERS: f32 = 30.87;
+const VOID_FILL_PASSES: usize = 64;
+
+pub struct DemGrid {
+    pub size: usize,
+    // East-west spacing of the columns.
+    pub cell_size: f32,
+    // North-south spacing of the rows relative to `cell_size`, geographic tiles have narrower
+    // columns away from the equator.
+    pub row_scale: f32,
+    // Rows north to south, voids are NaN.
+    pub elevations: Vec<f32>,
+}
+
+impl DemGrid {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("hgt") => {
+                let name = path.file_stem().and_then(|name| name.to_str()).unwrap_or_default();
+                let latitude = hgt_latitude(name)
+                    .ok_or_else(|| format!("SRTM tile name {} doesn't encode its latitude, like N45E006", name))?;
+                Self::from_hgt(&fs::read(path).map_err(|e| e.to_string())?, latitude)
+            }
+            Some("asc") => Self::from_ascii_grid(&fs::read_to_string(path).map_err(|e| e.to_string())?),
+            _ => Err(format!("unsupported DEM format: {}", path.display())),
+        }
+    }
+
+    // `latitude` is the tile's centre in degrees.
+    pub fn from_hgt(bytes: &[u8], latitude: f32) -> Result<Self, String> {
+        let size = ((bytes.len() / 2) as f64).sqrt() as usize;
+        if size * size * 2 != bytes.len() {
+            return Err(format!("{} bytes is not a square SRTM tile", bytes.len()));
+        }
+
+        let arc_seconds = match size {
+            3601 => 1.0,
+            1201 => 3.0,
+            _ => return Err(format!("unknown SRTM resolution {}x{}", size, size)),
+        };
+
+        let elevations = bytes
+            .chunks_exact(2)
+            .map(|b| i16::from_be_bytes([b[0], b[1]]))
+            .map(|e| if e == SRTM_VOID { f32::NAN } else { e as f32 })
+            .collect();
+
+        let row_spacing = arc_seconds * ARC_SECOND_METERS;
+        let cell_size = row_spacing * latitude.to_radians().cos();
+
+        Ok(Self { size, cell_size, row_scale: row_spacing / cell_size, elevations })
+    }
+
+    pub fn from_ascii_grid(contents: &str) -> Result<Self, String> {
+        let mut tokens = contents.split_whitespace().peekable();
+        let mut ncols = None;
+        let mut nrows = None;
+        let mut cell_size = None;
+        let mut nodata = None;
+
+        while let Some(key) = tokens.next_if(|t| t.starts_with(|c: char| c.is_ascii_alphabetic())) {
+            let value = tokens.next().ok_or_else(|| format!("missing value for {}", key))?;
+            let parse = || value.parse::<f32>().map_err(|e| format!("{}: {}", key, e));
+
+            match key.to_ascii_lowercase().as_str() {
+                "ncols" => ncols = Some(parse()? as usize),
+                "nrows" => nrows = Some(parse()? as usize),
+                "cellsize" => cell_size = Some(parse()?),
+                "nodata_value" => nodata = Some(parse()?),
+                _ => (),
+            }
+        }
+
+        let (Some(ncols), Some(nrows), Some(cell_size)) = (ncols, nrows, cell_size) else {
+            return Err("ASCII grid header is missing ncols, nrows or cellsize".into());
+        };
+
+        let values = tokens
+            .map(|t| t.parse::<f32>().map_err(|e| e.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if values.len() != ncols * nrows {
+            return Err(format!("expected {} values, found {}", ncols * nrows, values.len()));
+        }
+
+        // Crop to the centered square, the terrain is always square.
+        let size = ncols.min(nrows);
+        if size < 2 {
+            return Err(format!("{}x{} grid is too small", ncols, nrows));
+        }
+
+        let (x0, y0) = ((ncols - size) / 2, (nrows - size) / 2);
+        let elevations = (0..size * size)
+            .map(|i| values[(y0 + i / size) * ncols + x0 + i % size])
+            .map(|e| if Some(e) == nodata { f32::NAN } else { e })
+            .collect();
+
+        Ok(Self { size, cell_size, row_scale: 1.0, elevations })
+    }
+
+    // Side of the square that gets imported, east-west across the whole grid. Pixel is area, like
+    // the terrain and the GeoTIFF and ASCII grid exports, so an exported map imports back at the same scale.
+    pub fn extent(&self) -> f32 {
+        self.cell_size * self.size as f32
+    }
+
+    pub fn fill_voids(&mut self) {
+        let size = self.size;
+
+        for _ in 0..VOID_FILL_PASSES {
+            let voids = (0..self.elevations.len()).filter(|&i| self.elevations[i].is_nan()).collect::<Vec<_>>();
+            if voids.is_empty() {
+                return;
+            }
+
+            let filled = voids.iter().map(|&i| {
+                let (x, y) = (i % size, i / size);
+                let neighbors = [
+                    (x > 0).then(|| i - 1),
+                    (x + 1 < size).then(|| i + 1),
+                    (y > 0).then(|| i - size),
+                    (y + 1 < size).then(|| i + size),
+                ];
+
+                let valid = neighbors.into_iter().flatten().map(|n| self.elevations[n]).filter(|e| !e.is_nan());
+                let (sum, count) = valid.fold((0.0, 0), |(sum, count), e| (sum + e, count + 1));
+                if count > 0 { sum / count as f32 } else { f32::NAN }
+            }).collect::<Vec<_>>();
+
+            for (i, e) in voids.into_iter().zip(filled) {
+                self.elevations[i] = e;
+            }
+        }
+
+        let min = self.min_max().0;
+        for e in self.elevations.iter_mut().filter(|e| e.is_nan()) {
+            *e = min;
+        }
+    }
+
+    pub fn min_max(&self) -> (f32, f32) {
+        self.elevations.iter().filter(|e| !e.is_nan()).fold((f32::MAX, f32::MIN), |(min, max), &e| (min.min(e), max.max(e)))
+    }
+
+    // Bilinear, `u` and `v` span the outer edges of the grid's cells east-west and the same
+    // distance north-south around the centre.
+    pub fn sample(&self, u: f32, v: f32) -> f32 {
+        let v = 0.5 + (v - 0.5) / self.row_scale;
+        let max = (self.size - 1) as f32;
+        let size = self.size as f32;
+        let (x, y) = ((u * size - 0.5).clamp(0.0, max), (v * size - 0.5).clamp(0.0, max));
+        let (x0, y0) = (x as usize, y as usize);
+        let (x1, y1) = ((x0 + 1).min(self.size - 1), (y0 + 1).min(self.size - 1));
+        let (fx, fy) = (x.fract(), y.fract());
+
+        let at = |x: usize, y: usize| self.elevations[y * self.size + x];
+        let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+        let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+// Centre latitude of an SRTM tile from its name, which gives the south west corner.
+pub fn hgt_latitude(name: &str) -> Option<f32> {
+    let sign = match name.get(..1)?.to_ascii_uppercase().as_str() {
+        "N" => 1.0,
+        "S" => -1.0,
+        _ => return None,
+    };
+    let degrees = name.get(1..3)?.parse::<f32>().ok()?;
+
+    Some(sign * degrees + 0.5)
+}
+
+#[derive(Resource)]
+pub struct MountainDemImport {
+    pub path: String,
+    pub min_elevation: f32,
+    pub max_elevation: f32,
+    pub cell_size: f32,
+    pub terrain_height: f32,
+    applied: bool,
+}
+
+pub fn import_dem(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut height_evw: EventWriter<MountainHeightChanged>,
+    mut queue: ResMut<MountainComputeQueue>,
+    compute_textures: Res<MountainComputeTextures>,
+    terrain_settings: Res<MountainTerrainSettings>,
+) {
+    let Some(path) = crate::launch_arg("--dem") else { return };
+
+    let mut dem = match DemGrid::load(&path) {
+        Ok(dem) => dem,
+        Err(e) => {
+            // Startup skipped generating the heightmap for the import.
+            error!("Failed to import DEM {}, generating a heightmap instead: {}", path, e);
+            queue.request(FBM_STAGE);
+            return;
+        }
+    };
+
+    dem.fill_voids();
+    let (min, max) = dem.min_max();
+    let range = (max - min).max(f32::EPSILON);
+
+    let map = images.get_mut(compute_textures.map()).unwrap();
+    for (i, texel) in map.data.chunks_exact_mut(16).enumerate() {
+        let (x, y) = (i as u32 % TEXTURE_SIZE, i as u32 / TEXTURE_SIZE);
+        let uv = ((x as f32 + 0.5) / TEXTURE_SIZE as f32, (y as f32 + 0.5) / TEXTURE_SIZE as f32);
+        let height = (dem.sample(uv.0, uv.1) - min) / range;
+
+        texel[0..4].copy_from_slice(&height.to_le_bytes());
+    }
+
+    height_evw.send(MountainHeightChanged);
+
+    let import = MountainDemImport {
+        path,
+        min_elevation: min,
+        max_elevation: max,
+        cell_size: dem.cell_size,
+        terrain_height: range * terrain_settings.world_size / dem.extent(),
+        applied: false,
+    };
+    info!(
+        "Imported {} ({}m to {}m, {}m cells)",
+        import.path, import.min_elevation, import.max_elevation, import.cell_size,
+    );
+    commands.insert_resource(import);
+}
+
+pub fn apply_dem_scale(
+    import: Option<ResMut<MountainDemImport>>,
+    terrain: Res<MountainTerrain>,
+    mut materials: ResMut<Assets<MountainMaterial>>,
+) {
+    let Some(mut import) = import else { return };
+    if import.applied {
+        return;
+    }
+
+    materials.get_mut(&terrain.material).unwrap().settings.terrain_height = import.terrain_height;
+    import.applied = true;
+}
+
+// Grayscale image resized onto the erosion mask, white lets droplets spawn.
+pub fn load_erosion_mask(path: impl AsRef<Path>, images: &mut Assets<Image>, compute_textures: &MountainComputeTextures) -> Result<(), String> {
+    let mask = image::open(path)
+        .map_err(|e| e.to_string())?
+        .resize_exact(MASK_SIZE, MASK_SIZE, image::imageops::FilterType::Triangle)
+        .into_luma16();
+
+    let image = images.get_mut(&compute_textures.erosion_mask).unwrap();
+    for (texel, value) in image.data.chunks_exact_mut(4).zip(mask.pixels()) {
+        texel.copy_from_slice(&(value.0[0] as f32 / u16::MAX as f32).to_le_bytes());
+    }
+
+    Ok(())
+}
+
+pub fn import_erosion_mask(
+    mut images: ResMut<Assets<Image>>,
+    mut compute_settings: ResMut<MountainComputeSettings>,
+    compute_textures: Res<MountainComputeTextures>,
+) {
+    let Some(path) = crate::launch_arg("--erosion-mask") else { return };
+
+    if let Err(e) = load_erosion_mask(&path, &mut images, &compute_textures) {
+        error!("Failed to import erosion mask {}: {}", path, e);
+        return;
+    }
+
+    compute_settings.masked_erosion = true;
+    info!("Imported erosion mask {}", path);
+}
+
+pub struct MountainImportPlugin;
+
+impl Plugin for MountainImportPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_systems(PostStartup, (import_dem, import_erosion_mask))
+            .add_systems(Update, apply_dem_scale);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hgt_bytes(size: usize, elevation: impl Fn(usize) -> i16) -> Vec<u8> {
+        (0..size * size).flat_map(|i| elevation(i).to_be_bytes()).collect()
+    }
+
+    fn grid(size: usize, elevations: Vec<f32>) -> DemGrid {
+        DemGrid { size, cell_size: 1.0, row_scale: 1.0, elevations }
+    }
+
+    #[test]
+    fn parses_hgt_tiles() {
+        let bytes = hgt_bytes(1201, |i| if i == 5 { SRTM_VOID } else { (i % 1000) as i16 });
+        let dem = DemGrid::from_hgt(&bytes, 0.0).unwrap();
+
+        assert_eq!(dem.size, 1201);
+        assert_eq!(dem.cell_size, 3.0 * ARC_SECOND_METERS);
+        assert_eq!(dem.row_scale, 1.0);
+        assert_eq!(dem.elevations[4], 4.0);
+        assert!(dem.elevations[5].is_nan());
+        assert_eq!(dem.elevations[1201], 201.0);
+    }
+
+    #[test]
+    fn hgt_columns_narrow_with_latitude() {
+        let dem = DemGrid::from_hgt(&hgt_bytes(1201, |_| 0), 60.0).unwrap();
+
+        assert!((dem.cell_size - 1.5 * ARC_SECOND_METERS).abs() < 1e-3);
+        assert!((dem.row_scale - 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn rejects_malformed_hgt_tiles() {
+        assert!(DemGrid::from_hgt(&[0; 7], 0.0).is_err());
+        assert!(DemGrid::from_hgt(&hgt_bytes(100, |_| 0), 0.0).is_err());
+    }
+
+    #[test]
+    fn hgt_latitude_from_tile_name() {
+        assert_eq!(hgt_latitude("N45E006"), Some(45.5));
+        assert_eq!(hgt_latitude("s12w077"), Some(-11.5));
+        assert_eq!(hgt_latitude("E45N006"), None);
+        assert_eq!(hgt_latitude("N4"), None);
+        assert_eq!(hgt_latitude(""), None);
+    }
+
+    #[test]
+    fn parses_ascii_grids_cropped_to_a_square() {
+        let contents = "ncols 4\nnrows 2\nxllcorner 0\nyllcorner 0\ncellsize 30\nNODATA_value -9999\n\
+            1 2 3 4\n5 -9999 7 8\n";
+        let dem = DemGrid::from_ascii_grid(contents).unwrap();
+
+        assert_eq!(dem.size, 2);
+        assert_eq!(dem.cell_size, 30.0);
+        assert_eq!(dem.row_scale, 1.0);
+        assert_eq!(dem.elevations[..2], [2.0, 3.0]);
+        assert!(dem.elevations[2].is_nan());
+        assert_eq!(dem.elevations[3], 7.0);
+    }
+
+    #[test]
+    fn rejects_malformed_ascii_grids() {
+        assert!(DemGrid::from_ascii_grid("ncols 2\nnrows 2\n1 2 3 4").is_err());
+        assert!(DemGrid::from_ascii_grid("ncols 2\nnrows 2\ncellsize 1\n1 2 3").is_err());
+        assert!(DemGrid::from_ascii_grid("ncols 2\nnrows 2\ncellsize 1\n1 2 x 4").is_err());
+        assert!(DemGrid::from_ascii_grid("ncols 1\nnrows 1\ncellsize 1\n1").is_err());
+        assert!(DemGrid::from_ascii_grid("ncols 3\nnrows 1\ncellsize 1\n1 2 3").is_err());
+    }
+
+    #[test]
+    fn fill_voids_averages_neighbours() {
+        let mut dem = grid(3, vec![
+            1.0, 2.0, 3.0,
+            4.0, f32::NAN, 6.0,
+            7.0, 8.0, 9.0,
+        ]);
+        dem.fill_voids();
+
+        assert_eq!(dem.elevations[4], 5.0);
+    }
+
+    #[test]
+    fn fill_voids_grows_into_large_voids() {
+        let mut dem = grid(4, vec![f32::NAN; 16]);
+        dem.elevations[0] = 2.0;
+        dem.fill_voids();
+
+        assert!(dem.elevations.iter().all(|&e| e == 2.0));
+    }
+
+    #[test]
+    fn fill_voids_falls_back_to_the_minimum() {
+        // The far corner is more passes away than the fill runs.
+        let mut dem = grid(70, vec![f32::NAN; 70 * 70]);
+        dem.elevations[0] = 2.0;
+        dem.elevations[1] = 4.0;
+        dem.fill_voids();
+
+        assert!(dem.elevations.iter().all(|e| !e.is_nan()));
+        assert_eq!(dem.elevations[70 * 70 - 1], dem.min_max().0);
+    }
+
+    #[test]
+    fn samples_texel_centres_exactly() {
+        let dem = grid(2, vec![0.0, 1.0, 2.0, 3.0]);
+
+        assert_eq!(dem.sample(0.25, 0.25), 0.0);
+        assert_eq!(dem.sample(0.75, 0.25), 1.0);
+        assert_eq!(dem.sample(0.25, 0.75), 2.0);
+        assert_eq!(dem.sample(0.5, 0.5), 1.5);
+        // Outside the centres the edge texels extend to the outer cell edges.
+        assert_eq!(dem.sample(0.0, 0.0), 0.0);
+        assert_eq!(dem.sample(1.0, 1.0), 3.0);
+        assert_eq!(dem.extent(), 2.0);
+    }
+}
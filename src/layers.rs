@@ -0,0 +1,91 @@
+use bevy::{
+    asset::LoadState,
+    prelude::*,
+    render::texture::{ImageAddressMode, ImageLoaderSettings, ImageSampler, ImageSamplerDescriptor},
+};
+
+use crate::{material::MountainMaterial, terrain::MountainTerrain};
+
+const LAYER_TEXTURES: [(&str, bool); 3] = [("albedo", true), ("normal", false), ("roughness", false)];
+
+// Square layers stacked vertically in one image per texture kind, the layer count is
+// `height / width`. Palette entries pick a layer with `ColorEntry::layer`.
+#[derive(Resource)]
+pub struct MountainTerrainLayers {
+    pub dir: String,
+    pub images: [Handle<Image>; 3],
+    applied: bool,
+}
+
+pub fn load_terrain_layers(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+) {
+    let Some(dir) = crate::launch_arg("--layers") else { return };
+
+    let images = LAYER_TEXTURES.map(|(name, is_srgb)| {
+        asset_server.load_with_settings(format!("{}/{}.png", dir, name), move |settings: &mut ImageLoaderSettings| {
+            settings.is_srgb = is_srgb;
+            settings.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+                address_mode_u: ImageAddressMode::Repeat,
+                address_mode_v: ImageAddressMode::Repeat,
+                ..ImageSamplerDescriptor::linear()
+            });
+        })
+    });
+
+    commands.insert_resource(MountainTerrainLayers { dir, images, applied: false });
+}
+
+pub fn apply_terrain_layers(
+    layers: Option<ResMut<MountainTerrainLayers>>,
+    asset_server: Res<AssetServer>,
+    terrain: Res<MountainTerrain>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<MountainMaterial>>,
+) {
+    let Some(mut layers) = layers else { return };
+    if layers.applied {
+        return;
+    }
+
+    if layers.images.iter().any(|image| asset_server.get_load_state(image) == Some(LoadState::Failed)) {
+        error!("Failed to load terrain layers from {}", layers.dir);
+        layers.applied = true;
+        return;
+    }
+    if !layers.images.iter().all(|image| images.contains(image)) {
+        return;
+    }
+
+    let size = images.get(&layers.images[0]).unwrap().size();
+    if layers.images.iter().any(|image| images.get(image).unwrap().size() != size) || size.y % size.x != 0 {
+        error!("Terrain layers in {} must be the same size with square layers stacked vertically", layers.dir);
+        layers.applied = true;
+        return;
+    }
+
+    let layer_count = size.y / size.x;
+    for handle in layers.images.iter() {
+        images.get_mut(handle).unwrap().reinterpret_stacked_2d_as_array(layer_count);
+    }
+
+    let mat = materials.get_mut(&terrain.material).unwrap();
+    mat.albedo_layers = Some(layers.images[0].clone());
+    mat.normal_layers = Some(layers.images[1].clone());
+    mat.roughness_layers = Some(layers.images[2].clone());
+    mat.settings.layer_count = layer_count;
+
+    info!("Loaded {} terrain layers from {}", layer_count, layers.dir);
+    layers.applied = true;
+}
+
+pub struct MountainLayersPlugin;
+
+impl Plugin for MountainLayersPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_systems(Startup, load_terrain_layers)
+            .add_systems(Update, apply_terrain_layers);
+    }
+}
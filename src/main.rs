@@ -1,16 +1,28 @@
 use std::f32::consts::PI;
 
-use bevy::{prelude::*, render::{mesh::{Indices, PrimitiveTopology}, render_asset::RenderAssetUsages}, window::PresentMode};
-use bevy_image_export::{ImageExportBundle, ImageExportPlugin, ImageExportSettings, ImageExportSource};
+use bevy::{prelude::*, window::PresentMode};
+use bevy_image_export::ImageExportPlugin;
 use bevy_inspector_egui::quick::{AssetInspectorPlugin, ResourceInspectorPlugin};
 use bevy_panorbit_camera::{PanOrbitCamera, PanOrbitCameraPlugin};
 use bevy_screen_diagnostics::{ScreenDiagnosticsPlugin, ScreenFrameDiagnosticsPlugin};
-use compute::{uniforms::{MountainComputeSettings, MountainComputeTextures, MountainErosionTrigger, PrepareWriteCompute, RegenerateMountain, RegenerateShadows}, MountainComputePlugin};
-use material::{MountainMaterial, MountainMaterialPlugin};
+use compute::{history::{MountainHistorySettings, RedoHeightmap, UndoHeightmap}, sculpt::MountainSculptSettings, uniforms::{MountainBakeSettings, MountainComputeSettings, MountainErosionBatchSettings, MountainErosionProgress, MountainErosionTrigger, RegenerateAmbientOcclusion, RegenerateMountain, RegenerateShadows}, MountainComputePlugin};
+use export::{capture::{CaptureRender, MountainCaptureSettings}, ExportHeightmap, MountainExportPlugin, MountainExportSettings};
+use import::MountainImportPlugin;
+use layers::MountainLayersPlugin;
+use material::{CycleViewMode, MountainMaterial, MountainMaterialPlugin, MountainPalette, MountainSun, ReloadPalette};
+use sky::MountainSkyPlugin;
+use terrain::{MountainTerrainPlugin, MountainTerrainSettings};
+use time_of_day::{MountainTimeOfDay, MountainTimeOfDayPlugin, RecordSunSequence};
 
 mod material;
 mod compute;
+mod export;
+mod import;
+mod layers;
 mod settings;
+mod sky;
+mod terrain;
+mod time_of_day;
 
 fn main() {
     let export_plugin = ImageExportPlugin::default();
@@ -30,9 +42,27 @@ fn main() {
             ScreenFrameDiagnosticsPlugin,
             MountainMaterialPlugin,
             MountainComputePlugin,
+            MountainExportPlugin,
+            MountainImportPlugin,
+            MountainLayersPlugin,
+            MountainTerrainPlugin,
+            MountainSkyPlugin,
+            MountainTimeOfDayPlugin,
+            export_plugin,
+        ))
+        .add_plugins((
             ResourceInspectorPlugin::<MountainComputeSettings>::default(),
+            ResourceInspectorPlugin::<MountainErosionProgress>::default(),
+            ResourceInspectorPlugin::<MountainErosionBatchSettings>::default(),
+            ResourceInspectorPlugin::<MountainBakeSettings>::default(),
+            ResourceInspectorPlugin::<MountainSculptSettings>::default(),
+            ResourceInspectorPlugin::<MountainHistorySettings>::default(),
+            ResourceInspectorPlugin::<MountainExportSettings>::default(),
+            ResourceInspectorPlugin::<MountainCaptureSettings>::default(),
+            ResourceInspectorPlugin::<MountainTerrainSettings>::default(),
+            ResourceInspectorPlugin::<MountainTimeOfDay>::default(),
+            ResourceInspectorPlugin::<MountainPalette>::default(),
             AssetInspectorPlugin::<MountainMaterial>::default(),
-            export_plugin,
         ))
 
         .add_systems(Startup, setup)
@@ -45,8 +75,6 @@ fn main() {
 
 fn setup(
     mut commands: Commands,
-    mut materials: ResMut<Assets<MountainMaterial>>,
-    mut meshes: ResMut<Assets<Mesh>>,
 ) {
     commands.spawn((
         Camera3dBundle {
@@ -65,28 +93,33 @@ fn setup(
     ));
 
     commands.spawn((
-        MaterialMeshBundle {
-            mesh: meshes.add(create_mountain_plane()),
-            material: materials.add(MountainMaterial::default()),
-            transform: Transform::from_xyz(0.0, 0.0, 0.0),
+        DirectionalLightBundle {
+            directional_light: DirectionalLight {
+                shadows_enabled: true,
+                ..default()
+            },
             ..default()
         },
+        MountainSun,
     ));
-
 }
 
 #[allow(clippy::too_many_arguments)]
 fn keybinds(
-    mut commands: Commands,
     keys: Res<ButtonInput<KeyCode>>,
     mut gen_fbm_evw: EventWriter<RegenerateMountain>,
     mut gen_shadow_evw: EventWriter<RegenerateShadows>,
+    mut gen_ao_evw: EventWriter<RegenerateAmbientOcclusion>,
     mut erosion_evw: EventWriter<MountainErosionTrigger>,
-    mut prepare_write_evw: EventWriter<PrepareWriteCompute>,
-    mut prepared_write: Local<bool>,
-    mut export_sources: ResMut<Assets<ImageExportSource>>,
-    compute_textures: Res<MountainComputeTextures>,
-    image_exports: Query<Entity, With<ImageExportSettings>>,
+    mut export_evw: EventWriter<ExportHeightmap>,
+    mut capture_evw: EventWriter<CaptureRender>,
+    mut view_mode_evw: EventWriter<CycleViewMode>,
+    mut palette_evw: EventWriter<ReloadPalette>,
+    mut sequence_evw: EventWriter<RecordSunSequence>,
+    mut time_of_day: ResMut<MountainTimeOfDay>,
+    mut sculpt_settings: ResMut<MountainSculptSettings>,
+    mut undo_evw: EventWriter<UndoHeightmap>,
+    mut redo_evw: EventWriter<RedoHeightmap>,
 ) {
     if keys.just_pressed(KeyCode::KeyR) {
         gen_fbm_evw.send(RegenerateMountain);
@@ -96,71 +129,55 @@ fn keybinds(
         gen_shadow_evw.send(RegenerateShadows);
     }
 
+    if keys.just_pressed(KeyCode::KeyO) {
+        gen_ao_evw.send(RegenerateAmbientOcclusion);
+    }
+
     if keys.just_pressed(KeyCode::KeyE) {
         erosion_evw.send(MountainErosionTrigger::Toggle);
     }
 
-    for entity in image_exports.iter() {
-        commands.entity(entity).despawn();
+    if keys.just_pressed(KeyCode::KeyW) {
+        export_evw.send(ExportHeightmap);
     }
 
-    if *prepared_write {
-        *prepared_write = false;
-        commands.spawn(ImageExportBundle {
-            source: export_sources.add(compute_textures.map.clone()),
-            settings: ImageExportSettings {
-                output_dir: "heightmaps".into(),
-                extension: "exr".into(),
-            }
-        });
+    if keys.just_pressed(KeyCode::KeyC) {
+        capture_evw.send(CaptureRender);
     }
 
-    if keys.just_pressed(KeyCode::KeyW) {
-        prepare_write_evw.send(PrepareWriteCompute);
-        *prepared_write = true;
+    if keys.just_pressed(KeyCode::KeyV) {
+        view_mode_evw.send(CycleViewMode);
     }
-}
 
-const PLANE_LENGTH: f32 = 256.0;
-const PLANE_RES: usize = 8;
+    if keys.just_pressed(KeyCode::KeyL) {
+        palette_evw.send(ReloadPalette);
+    }
 
-fn create_mountain_plane() -> Mesh {
-    let half_length = PLANE_LENGTH * 0.5;
-    let side_vert_count = PLANE_LENGTH as usize * PLANE_RES;
+    if keys.just_pressed(KeyCode::KeyP) {
+        time_of_day.playing = !time_of_day.playing;
+    }
 
-    let mut positions = vec![];
-    let mut uvs = vec![];
+    if keys.just_pressed(KeyCode::KeyT) {
+        sequence_evw.send(RecordSunSequence);
+    }
 
-    for x in 0..=side_vert_count {
-        for z in 0..=side_vert_count {
-            positions.push(Vec3::new(
-                (x as f32 / side_vert_count as f32 * PLANE_LENGTH) - half_length,
-                0.0,
-                (z as f32 / side_vert_count as f32 * PLANE_LENGTH) - half_length,
-            ));
-            uvs.push(Vec2::new(x as f32 / side_vert_count as f32, z as f32 / side_vert_count as f32));
-        }
+    if keys.just_pressed(KeyCode::KeyB) {
+        sculpt_settings.enabled = !sculpt_settings.enabled;
     }
 
-    let mut indices = vec![0u32; side_vert_count * side_vert_count * 6];
-
-    let mut vi = 0u32;
-    for _x in 0..side_vert_count {
-        for _z in 0..side_vert_count {
-            indices.push(vi);
-            indices.push(vi + 1);
-            indices.push(vi + side_vert_count as u32 + 2);
-            indices.push(vi);
-            indices.push(vi + side_vert_count as u32 + 2);
-            indices.push(vi + side_vert_count as u32 + 1);
-            vi += 1;
+    if keys.just_pressed(KeyCode::KeyZ) && keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]) {
+        if keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+            redo_evw.send(RedoHeightmap);
+        } else {
+            undo_evw.send(UndoHeightmap);
         }
-        vi += 1;
     }
+}
+
+fn launch_arg(name: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != name).nth(1)
+}
 
-    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::RENDER_WORLD);
-    mesh.insert_indices(Indices::U32(indices));
-    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
-    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
-    mesh
+fn launch_flag(name: &str) -> bool {
+    std::env::args().any(|arg| arg == name)
 }
@@ -1,15 +1,32 @@
-use bevy::{asset::load_internal_asset, prelude::*, render::render_resource::AsBindGroup};
+use bevy::{
+    asset::load_internal_asset,
+    pbr::{ExtendedMaterial, MaterialExtension, MaterialExtensionKey, MaterialExtensionPipeline},
+    prelude::*,
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_asset::RenderAssets,
+        render_resource::{AsBindGroup, AsBindGroupShaderType, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError},
+    },
+};
 
-use crate::{compute::uniforms::{MountainComputeSettings, MountainComputeTextures}, settings::{ColorEntry, MountainRenderSettings}};
+use crate::{
+    compute::{sculpt::MountainSculptSettings, stage::schedule_compute_stages, uniforms::{MountainComputeSettings, MountainComputeTextures}},
+    settings::{self, ColorEntry, MountainRenderSettings, MountainRenderUniform, MOUNTAIN_COLORS},
+    terrain::{MountainTerrain, MountainTerrainSettings},
+};
 
 pub const MOUNTAIN_MATERIAL_HANDLE: Handle<Shader> =
     Handle::weak_from_u128(0x243e54999439800056177abc27c63000);
+pub const MOUNTAIN_TERRAIN_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(0x243e54999439800056177abc27c63001);
+
+pub type MountainPbrMaterial = ExtendedMaterial<StandardMaterial, MountainExtension>;
 
 
 #[derive(AsBindGroup, Debug, Reflect, Clone, Asset)]
 #[reflect(Debug, Default)]
+#[uniform(0, MountainRenderUniform)]
 pub struct MountainMaterial {
-    #[uniform(0, visibility(vertex, fragment))]
     pub settings: MountainRenderSettings,
 
     #[texture(1, visibility(vertex, fragment), dimension = "2d")]
@@ -17,35 +34,250 @@ pub struct MountainMaterial {
     pub map: Option<Handle<Image>>,
 
     #[storage(3, visibility(fragment), read_only)]
-    pub colors: [ColorEntry; 7],
+    pub colors: Vec<ColorEntry>,
+
+    #[texture(4, visibility(fragment), dimension = "2d_array")]
+    #[sampler(5)]
+    pub albedo_layers: Option<Handle<Image>>,
+
+    #[texture(6, visibility(fragment), dimension = "2d_array")]
+    #[sampler(7)]
+    pub normal_layers: Option<Handle<Image>>,
+
+    #[texture(8, visibility(fragment), dimension = "2d_array")]
+    #[sampler(9)]
+    pub roughness_layers: Option<Handle<Image>>,
+
+    #[texture(10, visibility(fragment), sample_type = "float", filterable = false)]
+    pub erosion_mask: Option<Handle<Image>>,
+}
+
+impl AsBindGroupShaderType<MountainRenderUniform> for MountainMaterial {
+    fn as_bind_group_shader_type(&self, _images: &RenderAssets<Image>) -> MountainRenderUniform {
+        (&self.settings).into()
+    }
 }
 
 impl Material for MountainMaterial {
-    fn fragment_shader() -> bevy::render::render_resource::ShaderRef {
+    fn fragment_shader() -> ShaderRef {
+        "shaders/mountain.wgsl".into()
+    }
+
+    fn vertex_shader() -> ShaderRef {
         "shaders/mountain.wgsl".into()
     }
 
-    fn vertex_shader() -> bevy::render::render_resource::ShaderRef {
+    fn prepass_vertex_shader() -> ShaderRef {
         "shaders/mountain.wgsl".into()
     }
 }
 
+// Same bindings as `MountainMaterial`, moved past the `StandardMaterial` ones.
+#[derive(AsBindGroup, Debug, Reflect, Clone, Asset)]
+#[reflect(Debug, Default)]
+#[uniform(100, MountainRenderUniform)]
+pub struct MountainExtension {
+    pub settings: MountainRenderSettings,
+
+    #[texture(101, visibility(vertex, fragment), dimension = "2d")]
+    #[sampler(102)]
+    pub map: Option<Handle<Image>>,
+
+    #[storage(103, visibility(fragment), read_only)]
+    pub colors: Vec<ColorEntry>,
+
+    #[texture(104, visibility(fragment), dimension = "2d_array")]
+    #[sampler(105)]
+    pub albedo_layers: Option<Handle<Image>>,
+
+    #[texture(106, visibility(fragment), dimension = "2d_array")]
+    #[sampler(107)]
+    pub normal_layers: Option<Handle<Image>>,
+
+    #[texture(108, visibility(fragment), dimension = "2d_array")]
+    #[sampler(109)]
+    pub roughness_layers: Option<Handle<Image>>,
+
+    #[texture(110, visibility(fragment), sample_type = "float", filterable = false)]
+    pub erosion_mask: Option<Handle<Image>>,
+}
+
+impl AsBindGroupShaderType<MountainRenderUniform> for MountainExtension {
+    fn as_bind_group_shader_type(&self, _images: &RenderAssets<Image>) -> MountainRenderUniform {
+        (&self.settings).into()
+    }
+}
+
+impl MaterialExtension for MountainExtension {
+    fn vertex_shader() -> ShaderRef {
+        "shaders/mountain.wgsl".into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        "shaders/mountain_pbr.wgsl".into()
+    }
+
+    fn prepass_vertex_shader() -> ShaderRef {
+        "shaders/mountain.wgsl".into()
+    }
+
+    fn deferred_vertex_shader() -> ShaderRef {
+        "shaders/mountain.wgsl".into()
+    }
+
+    fn deferred_fragment_shader() -> ShaderRef {
+        "shaders/mountain_pbr.wgsl".into()
+    }
+
+    fn specialize(
+        _pipeline: &MaterialExtensionPipeline,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayout,
+        _key: MaterialExtensionKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        descriptor.vertex.shader_defs.push("MOUNTAIN_EXTENSION".into());
+        if let Some(fragment) = descriptor.fragment.as_mut() {
+            fragment.shader_defs.push("MOUNTAIN_EXTENSION".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Component)]
+pub struct MountainSun;
+
+#[derive(Event)]
+pub struct CycleViewMode;
+
 pub fn prepare_mountain_material(
-    handles: Query<&Handle<MountainMaterial>>,
+    terrain: Res<MountainTerrain>,
     mut materials: ResMut<Assets<MountainMaterial>>,
+    mut pbr_materials: ResMut<Assets<MountainPbrMaterial>>,
     mountain_textures: Res<MountainComputeTextures>,
     compute_settings: Res<MountainComputeSettings>,
+    terrain_settings: Res<MountainTerrainSettings>,
+    sculpt_settings: Res<MountainSculptSettings>,
+) {
+    let mat = materials.get_mut(&terrain.material).unwrap();
+
+    mat.settings.pixel_size = 1.0 / compute_settings.map_size as f32;
+    mat.settings.sun_direction = compute_settings.sun_direction.normalize() * Vec3::new(1.0, -1.0, -1.0);
+    mat.settings.erosion_radius = compute_settings.erosion_radius;
+    mat.settings.world_size = terrain_settings.world_size;
+    mat.settings.lod_distance = terrain_settings.lod_distance();
+    mat.settings.morph_start = terrain_settings.morph_start;
+    mat.settings.patch_resolution = terrain_settings.patch_resolution;
+
+    mat.settings.show_erosion_mask = sculpt_settings.enabled && sculpt_settings.brush.paints_mask();
+
+    // Runs after `schedule_compute_stages`, so this is the map the compute node leaves this frame.
+    mat.map = Some(mountain_textures.map().clone());
+    if mat.erosion_mask.is_none() {
+        mat.erosion_mask = Some(mountain_textures.erosion_mask.clone());
+    }
+
+    // An empty storage buffer can't be bound.
+    if mat.colors.is_empty() {
+        mat.colors.push(ColorEntry::default());
+    }
+
+    let pbr = pbr_materials.get_mut(&terrain.pbr_material).unwrap();
+    pbr.extension.settings = mat.settings.clone();
+    pbr.extension.map = mat.map.clone();
+    pbr.extension.colors = mat.colors.clone();
+    pbr.extension.albedo_layers = mat.albedo_layers.clone();
+    pbr.extension.normal_layers = mat.normal_layers.clone();
+    pbr.extension.roughness_layers = mat.roughness_layers.clone();
+    pbr.extension.erosion_mask = mat.erosion_mask.clone();
+}
+
+// The occlusion bake works in texels, so it needs the terrain's vertical exaggeration.
+pub fn sync_ao_height_scale(
+    terrain: Res<MountainTerrain>,
+    materials: Res<Assets<MountainMaterial>>,
+    terrain_settings: Res<MountainTerrainSettings>,
+    mut compute_settings: ResMut<MountainComputeSettings>,
 ) {
-    for handle in handles.iter() {
-        let mat = materials.get_mut(handle).unwrap();
+    let Some(mat) = materials.get(&terrain.material) else { return };
+    let scale = mat.settings.terrain_height / terrain_settings.world_size;
+
+    if compute_settings.ao_height_scale != scale {
+        compute_settings.ao_height_scale = scale;
+    }
+}
+
+// RON palette on the terrain, empty for the built in colors. Loaded again whenever it changes.
+#[derive(Resource, Reflect, Clone, Default)]
+#[reflect(Resource)]
+pub struct MountainPalette {
+    pub path: String,
+}
 
-        mat.settings.pixel_size = 1.0 / compute_settings.map_size as f32;
-        mat.settings.sun_direction = compute_settings.sun_direction.normalize() * Vec3::new(1.0, -1.0, -1.0);
-        mat.settings.erosion_radius = compute_settings.erosion_radius;
+// Loads the palette file again, for edits made while running.
+#[derive(Event)]
+pub struct ReloadPalette;
 
-        if mat.map.is_none() {
-            mat.map = Some(mountain_textures.map.clone());
+pub fn load_palette(
+    palette: Res<MountainPalette>,
+    mut reload_evr: EventReader<ReloadPalette>,
+    terrain: Res<MountainTerrain>,
+    mut materials: ResMut<Assets<MountainMaterial>>,
+    mut pbr_materials: ResMut<Assets<MountainPbrMaterial>>,
+) {
+    if reload_evr.read().count() == 0 && !palette.is_changed() {
+        return;
+    }
+
+    let colors = if palette.path.is_empty() {
+        MOUNTAIN_COLORS.to_vec()
+    } else {
+        match settings::load_palette(&palette.path) {
+            Ok(colors) if !colors.is_empty() => {
+                info!("Loaded {} palette entries from {}", colors.len(), palette.path);
+                colors
+            }
+            Ok(_) => {
+                error!("Palette {} has no entries", palette.path);
+                return;
+            }
+            Err(e) => {
+                error!("Failed to load palette {}: {}", palette.path, e);
+                return;
+            }
         }
+    };
+
+    materials.get_mut(&terrain.material).unwrap().colors = colors.clone();
+    pbr_materials.get_mut(&terrain.pbr_material).unwrap().extension.colors = colors;
+}
+
+pub fn cycle_view_mode(
+    mut evr: EventReader<CycleViewMode>,
+    terrain: Res<MountainTerrain>,
+    mut materials: ResMut<Assets<MountainMaterial>>,
+) {
+    for _ in evr.read() {
+        let mat = materials.get_mut(&terrain.material).unwrap();
+        mat.settings.view_mode = mat.settings.view_mode.next();
+        info!("View mode: {:?}", mat.settings.view_mode);
+    }
+}
+
+// Points the directional light along the sun the shadow pass marches towards, with the
+// normalized heightmap axis scaled back to world units.
+pub fn update_sun(
+    mut suns: Query<&mut Transform, With<MountainSun>>,
+    terrain: Res<MountainTerrain>,
+    materials: Res<Assets<MountainMaterial>>,
+    compute_settings: Res<MountainComputeSettings>,
+    terrain_settings: Res<MountainTerrainSettings>,
+) {
+    let Some(mat) = materials.get(&terrain.material) else { return };
+    let world_size = terrain_settings.world_size;
+    let sun = compute_settings.sun_direction * Vec3::new(world_size, mat.settings.terrain_height, world_size);
+
+    for mut transform in suns.iter_mut() {
+        *transform = Transform::IDENTITY.looking_to(-sun, Vec3::Y);
     }
 }
 
@@ -60,10 +292,22 @@ impl Plugin for MountainMaterialPlugin {
             concat!(env!("CARGO_MANIFEST_DIR"), "/assets/shaders/mountain.wgsl"),
             Shader::from_wgsl
         );
+        load_internal_asset!(
+            app,
+            MOUNTAIN_TERRAIN_HANDLE,
+            concat!(env!("CARGO_MANIFEST_DIR"), "/assets/shaders/terrain.wgsl"),
+            Shader::from_wgsl
+        );
 
         app
             .add_plugins(MaterialPlugin::<MountainMaterial>::default())
-            .add_systems(Update, prepare_mountain_material)
+            .add_plugins(MaterialPlugin::<MountainPbrMaterial>::default())
+            .insert_resource(MountainPalette { path: crate::launch_arg("--palette").unwrap_or_default() })
+            .register_type::<MountainPalette>()
+            .add_event::<CycleViewMode>()
+            .add_event::<ReloadPalette>()
+            .add_systems(Update, (load_palette, sync_ao_height_scale, update_sun, cycle_view_mode))
+            .add_systems(PostUpdate, prepare_mountain_material.after(schedule_compute_stages))
             .register_type::<MountainMaterial>()
             .register_asset_reflect::<MountainMaterial>()
             .register_type::<Handle<MountainMaterial>>();
@@ -1,8 +1,11 @@
-use bevy::{math::Vec3, reflect::Reflect, render::render_resource::ShaderType};
+use std::fs;
 
-use crate::{compute::uniforms::EROSION_RADIUS, material::MountainMaterial};
+use bevy::{math::Vec3, prelude::ReflectDefault, reflect::Reflect, render::render_resource::ShaderType};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Reflect, ShaderType)]
+use crate::{compute::uniforms::EROSION_RADIUS, material::{MountainExtension, MountainMaterial}};
+
+#[derive(Debug, Clone, Reflect)]
 pub struct MountainRenderSettings {
     pub sun_direction: Vec3,
     pub terrain_height: f32,
@@ -11,6 +14,122 @@ pub struct MountainRenderSettings {
 
     pub normal_strength: f32,
     pub erosion_radius: i32,
+    pub world_size: f32,
+    pub lod_distance: f32,
+
+    pub morph_start: f32,
+    pub patch_resolution: u32,
+    pub texture_scale: f32,
+    pub triplanar_sharpness: f32,
+
+    pub layer_count: u32,
+    pub view_mode: MountainViewMode,
+    // Draw the erosion border tinted instead of discarding it.
+    pub show_border: bool,
+    // Tint the painted erosion mask, set while a mask brush is active.
+    pub show_erosion_mask: bool,
+
+    // Contour lines every `contour_interval` world units of elevation.
+    pub contours: bool,
+    pub contour_interval: f32,
+    // Every nth contour is drawn as a thicker index contour.
+    pub index_contour_every: u32,
+    // World space grid every `grid_spacing` units.
+    pub grid: bool,
+
+    pub grid_spacing: f32,
+    pub line_width: f32,
+
+    pub sky_zenith: Vec3,
+    // Extinction per world unit at zero elevation, 0 disables fog.
+    pub fog_density: f32,
+    pub sky_horizon: Vec3,
+    // How quickly the fog thins out with elevation.
+    pub fog_height_falloff: f32,
+    pub sun_color: Vec3,
+    // Angular radius of the sun disc in radians.
+    pub sun_disc_size: f32,
+    // Brightness of fully shadowed terrain, scaled down by the baked occlusion.
+    pub ambient: f32,
+    pub ao_strength: f32,
+}
+
+// `MountainRenderSettings` as the materials bind it, with enums and flags as u32.
+#[derive(Clone, ShaderType)]
+pub struct MountainRenderUniform {
+    pub sun_direction: Vec3,
+    pub terrain_height: f32,
+    pub blend_sharpness: f32,
+    pub pixel_size: f32,
+
+    pub normal_strength: f32,
+    pub erosion_radius: i32,
+    pub world_size: f32,
+    pub lod_distance: f32,
+
+    pub morph_start: f32,
+    pub patch_resolution: u32,
+    pub texture_scale: f32,
+    pub triplanar_sharpness: f32,
+
+    pub layer_count: u32,
+    pub view_mode: u32,
+    pub show_border: u32,
+    pub show_erosion_mask: u32,
+
+    pub contours: u32,
+    pub contour_interval: f32,
+    pub index_contour_every: u32,
+    pub grid: u32,
+
+    pub grid_spacing: f32,
+    pub line_width: f32,
+
+    pub sky_zenith: Vec3,
+    pub fog_density: f32,
+    pub sky_horizon: Vec3,
+    pub fog_height_falloff: f32,
+    pub sun_color: Vec3,
+    pub sun_disc_size: f32,
+    pub ambient: f32,
+    pub ao_strength: f32,
+}
+
+impl From<&MountainRenderSettings> for MountainRenderUniform {
+    fn from(settings: &MountainRenderSettings) -> Self {
+        Self {
+            sun_direction: settings.sun_direction,
+            terrain_height: settings.terrain_height,
+            blend_sharpness: settings.blend_sharpness,
+            pixel_size: settings.pixel_size,
+            normal_strength: settings.normal_strength,
+            erosion_radius: settings.erosion_radius,
+            world_size: settings.world_size,
+            lod_distance: settings.lod_distance,
+            morph_start: settings.morph_start,
+            patch_resolution: settings.patch_resolution,
+            texture_scale: settings.texture_scale,
+            triplanar_sharpness: settings.triplanar_sharpness,
+            layer_count: settings.layer_count,
+            view_mode: settings.view_mode as u32,
+            show_border: settings.show_border as u32,
+            show_erosion_mask: settings.show_erosion_mask as u32,
+            contours: settings.contours as u32,
+            contour_interval: settings.contour_interval,
+            index_contour_every: settings.index_contour_every,
+            grid: settings.grid as u32,
+            grid_spacing: settings.grid_spacing,
+            line_width: settings.line_width,
+            sky_zenith: settings.sky_zenith,
+            fog_density: settings.fog_density,
+            sky_horizon: settings.sky_horizon,
+            fog_height_falloff: settings.fog_height_falloff,
+            sun_color: settings.sun_color,
+            sun_disc_size: settings.sun_disc_size,
+            ambient: settings.ambient,
+            ao_strength: settings.ao_strength,
+        }
+    }
 }
 
 impl Default for MountainRenderSettings {
@@ -23,33 +142,100 @@ impl Default for MountainRenderSettings {
             
             normal_strength: 0.1,
             erosion_radius: EROSION_RADIUS,
+            world_size: 0.0,
+            lod_distance: 0.0,
+
+            morph_start: 0.0,
+            patch_resolution: 0,
+            texture_scale: 8.0,
+            triplanar_sharpness: 4.0,
+
+            layer_count: 0,
+            view_mode: MountainViewMode::Shaded,
+            show_border: false,
+            show_erosion_mask: false,
+
+            contours: false,
+            contour_interval: 5.0,
+            index_contour_every: 5,
+            grid: false,
+
+            grid_spacing: 16.0,
+            line_width: 1.0,
+
+            sky_zenith: Vec3::new(0.12, 0.3, 0.7),
+            fog_density: 0.002,
+            sky_horizon: Vec3::new(0.6, 0.72, 0.85),
+            fog_height_falloff: 0.02,
+            sun_color: Vec3::new(1.0, 0.9, 0.75),
+            sun_disc_size: 0.02,
+            ambient: 0.1,
+            ao_strength: 1.0,
         }
     }
 }
 
-#[derive(Debug, Reflect, Clone, Copy, ShaderType)]
+// NOTE: Discriminants are matched by `view_mode_color` in the terrain shader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Reflect)]
+pub enum MountainViewMode {
+    #[default]
+    Shaded,
+    Height,
+    Slope,
+    Curvature,
+    Shadow,
+    Normals,
+    Erosion,
+}
+
+impl MountainViewMode {
+    const ALL: [MountainViewMode; 7] = [
+        Self::Shaded, Self::Height, Self::Slope, Self::Curvature, Self::Shadow, Self::Normals, Self::Erosion,
+    ];
+
+    pub fn next(self) -> Self {
+        Self::ALL[(self as usize + 1) % Self::ALL.len()]
+    }
+}
+
+#[derive(Debug, Reflect, Clone, Copy, ShaderType, Serialize, Deserialize)]
+#[reflect(Default)]
 pub struct ColorEntry {
     pub color: [f32; 4],
     pub elevation: f32,
     pub steepness: f32,
-    _padding: [f32; 2],
+    pub roughness: f32,
+    // Index into the texture layer arrays, negative for a flat color.
+    pub layer: i32,
 }
 
 impl ColorEntry {
     #[inline]
-    pub const fn new(color: [f32; 4], elevation: f32, steepness: f32) -> Self {
-        Self { color, elevation, steepness, _padding: [0.0; 2] }
+    pub const fn new(color: [f32; 4], elevation: f32, steepness: f32, roughness: f32, layer: i32) -> Self {
+        Self { color, elevation, steepness, roughness, layer }
+    }
+}
+
+impl Default for ColorEntry {
+    fn default() -> Self {
+        Self::new([1.0; 4], 0.5, 0.5, 0.8, -1)
     }
 }
 
+// A palette file is a RON list of `ColorEntry`.
+pub fn load_palette(path: &str) -> Result<Vec<ColorEntry>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    ron::from_str(&contents).map_err(|e| e.to_string())
+}
+
 pub const MOUNTAIN_COLORS: [ColorEntry; 7] =  [
-    ColorEntry::new([0.52511, 0.33674, 0.22867, 0.0], 0.2, 0.2), // dirt
-    ColorEntry::new([0.522, 0.698, 0.349, 1.0], 0.25, 0.05), // grass
-    ColorEntry::new([0.396, 0.522, 0.255, 1.0], 0.5, 0.15), // bush
-    ColorEntry::new([0.278, 0.463, 0.271, 1.0], 0.8, 0.1), // forest
-    ColorEntry::new([0.427, 0.463, 0.529, 1.0], 0.3, 0.8), // stone
-    ColorEntry::new([0.518, 0.553, 0.604, 1.0], 0.9, 0.25), // slate
-    ColorEntry::new([0.824, 0.878, 0.871, 1.0], 0.99, 0.3), // snow
+    ColorEntry::new([0.52511, 0.33674, 0.22867, 0.0], 0.2, 0.2, 0.9, 0), // dirt
+    ColorEntry::new([0.522, 0.698, 0.349, 1.0], 0.25, 0.05, 0.8, 1), // grass
+    ColorEntry::new([0.396, 0.522, 0.255, 1.0], 0.5, 0.15, 0.85, 2), // bush
+    ColorEntry::new([0.278, 0.463, 0.271, 1.0], 0.8, 0.1, 0.9, 3), // forest
+    ColorEntry::new([0.427, 0.463, 0.529, 1.0], 0.3, 0.8, 0.6, 4), // stone
+    ColorEntry::new([0.518, 0.553, 0.604, 1.0], 0.9, 0.25, 0.5, 5), // slate
+    ColorEntry::new([0.824, 0.878, 0.871, 1.0], 0.99, 0.3, 0.35, 6), // snow
 ];
 
 
@@ -58,7 +244,65 @@ impl Default for MountainMaterial {
         Self {
             settings: MountainRenderSettings::default(),
             map: None,
-            colors: MOUNTAIN_COLORS,
+            colors: MOUNTAIN_COLORS.to_vec(),
+            albedo_layers: None,
+            normal_layers: None,
+            roughness_layers: None,
+            erosion_mask: None,
         }
     }
 }
+
+impl Default for MountainExtension {
+    fn default() -> Self {
+        Self {
+            settings: MountainRenderSettings::default(),
+            map: None,
+            colors: MOUNTAIN_COLORS.to_vec(),
+            albedo_layers: None,
+            normal_layers: None,
+            roughness_layers: None,
+            erosion_mask: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_palette_matches_builtin_colors() {
+        let colors = load_palette("assets/palettes/default.ron").unwrap();
+
+        assert_eq!(colors.len(), MOUNTAIN_COLORS.len());
+        for (loaded, builtin) in colors.iter().zip(MOUNTAIN_COLORS.iter()) {
+            assert_eq!(loaded.color, builtin.color);
+            assert_eq!(loaded.elevation, builtin.elevation);
+            assert_eq!(loaded.steepness, builtin.steepness);
+            assert_eq!(loaded.roughness, builtin.roughness);
+            assert_eq!(loaded.layer, builtin.layer);
+        }
+    }
+
+    #[test]
+    fn desert_palette_uses_flat_colors() {
+        let colors = load_palette("assets/palettes/desert.ron").unwrap();
+
+        assert!(!colors.is_empty());
+        assert!(colors.iter().all(|entry| entry.layer < 0));
+    }
+
+    #[test]
+    fn rejects_missing_and_malformed_palettes() {
+        assert!(load_palette("assets/palettes/missing.ron").is_err());
+
+        // Unique to the process, so concurrent runs don't write over each other.
+        let path = std::env::temp_dir().join(format!("mountain_malformed_palette_{}.ron", std::process::id()));
+        fs::write(&path, "[(color: (1.0, 1.0), elevation: 0.5)]").unwrap();
+        let result = load_palette(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+}
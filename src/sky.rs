@@ -0,0 +1,92 @@
+use bevy::{
+    pbr::{MaterialPipeline, MaterialPipelineKey, NotShadowCaster, NotShadowReceiver},
+    prelude::*,
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_asset::RenderAssets,
+        render_resource::{AsBindGroup, AsBindGroupShaderType, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError},
+    },
+};
+use bevy_panorbit_camera::PanOrbitCamera;
+
+use crate::{material::MountainMaterial, settings::{MountainRenderSettings, MountainRenderUniform}, terrain::MountainTerrain};
+
+const SKY_RADIUS: f32 = 4000.0;
+
+// Shares the terrain settings binding so `sky_color` from the terrain shader module can be used as is.
+#[derive(AsBindGroup, Debug, Reflect, Clone, Asset, Default)]
+#[uniform(0, MountainRenderUniform)]
+pub struct SkyMaterial {
+    pub settings: MountainRenderSettings,
+}
+
+impl AsBindGroupShaderType<MountainRenderUniform> for SkyMaterial {
+    fn as_bind_group_shader_type(&self, _images: &RenderAssets<Image>) -> MountainRenderUniform {
+        (&self.settings).into()
+    }
+}
+
+impl Material for SkyMaterial {
+    fn fragment_shader() -> ShaderRef {
+        "shaders/sky.wgsl".into()
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayout,
+        _key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        descriptor.primitive.cull_mode = None;
+        Ok(())
+    }
+}
+
+#[derive(Component)]
+pub struct MountainSky(Handle<SkyMaterial>);
+
+fn setup_sky(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<SkyMaterial>>,
+) {
+    let material = materials.add(SkyMaterial::default());
+
+    commands.spawn((
+        MaterialMeshBundle {
+            mesh: meshes.add(Sphere::new(SKY_RADIUS).mesh().uv(32, 18)),
+            material: material.clone(),
+            ..default()
+        },
+        NotShadowCaster,
+        NotShadowReceiver,
+        MountainSky(material),
+    ));
+}
+
+fn update_sky(
+    mut skies: Query<(&mut Transform, &MountainSky)>,
+    cameras: Query<&GlobalTransform, With<PanOrbitCamera>>,
+    terrain: Res<MountainTerrain>,
+    terrain_materials: Res<Assets<MountainMaterial>>,
+    mut materials: ResMut<Assets<SkyMaterial>>,
+) {
+    let Some(terrain_material) = terrain_materials.get(&terrain.material) else { return };
+    let Ok(camera) = cameras.get_single() else { return };
+
+    for (mut transform, sky) in skies.iter_mut() {
+        transform.translation = camera.translation();
+        materials.get_mut(&sky.0).unwrap().settings = terrain_material.settings.clone();
+    }
+}
+
+pub struct MountainSkyPlugin;
+
+impl Plugin for MountainSkyPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_plugins(MaterialPlugin::<SkyMaterial>::default())
+            .add_systems(Startup, setup_sky)
+            .add_systems(Update, update_sky);
+    }
+}
@@ -0,0 +1,346 @@
+use bevy::{
+    ecs::system::EntityCommands,
+    prelude::*,
+    render::{mesh::{Indices, PrimitiveTopology}, primitives::Aabb, render_asset::RenderAssetUsages},
+};
+use bevy_panorbit_camera::PanOrbitCamera;
+
+use crate::{compute::uniforms::MountainComputeSettings, material::{MountainMaterial, MountainPbrMaterial}};
+
+const MAX_LOD_DEPTH: u32 = 12;
+// Closer splits let neighbouring patches differ by more than one lod, which morphing can't hide.
+const MIN_LOD_DISTANCE: f32 = 2.0;
+
+#[derive(Reflect, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MountainShading {
+    // Palette colors with the baked shadow channel, ignores scene lights.
+    #[default]
+    Unlit,
+    // `StandardMaterial` extension lit by bevy lights and shadow maps.
+    Pbr,
+}
+
+#[derive(Resource, Reflect, Clone)]
+#[reflect(Resource)]
+pub struct MountainTerrainSettings {
+    pub world_size: f32,
+    // Vertices per world unit at the finest lod.
+    pub vertex_density: f32,
+    // Ignore `vertex_density` and put one vertex on every heightmap texel.
+    pub match_texels: bool,
+    // Quads per patch side, must be even so patches can morph onto their parent grid. Rounded up to
+    // a power of two while matching texels, so the finest patches split the map evenly.
+    pub patch_resolution: u32,
+    // A node is split while the camera is closer than `lod_distance` times its size, at least 2.
+    pub lod_distance: f32,
+    // Fraction of a node's range after which it starts morphing towards its parent.
+    pub morph_start: f32,
+    pub shading: MountainShading,
+}
+
+impl Default for MountainTerrainSettings {
+    fn default() -> Self {
+        Self {
+            world_size: 256.0,
+            vertex_density: 8.0,
+            match_texels: true,
+            patch_resolution: 64,
+            lod_distance: 2.0,
+            morph_start: 0.7,
+            shading: MountainShading::Unlit,
+        }
+    }
+}
+
+impl MountainTerrainSettings {
+    pub fn vertex_density(&self, map_size: u32) -> f32 {
+        if self.match_texels {
+            map_size as f32 / self.world_size
+        } else {
+            self.vertex_density
+        }
+    }
+
+    pub fn lod_distance(&self) -> f32 {
+        self.lod_distance.max(MIN_LOD_DISTANCE)
+    }
+
+    // What `patch_resolution` is snapped to.
+    pub fn valid_patch_resolution(&self) -> u32 {
+        let resolution = self.patch_resolution.max(2);
+        if self.match_texels {
+            resolution.next_power_of_two()
+        } else {
+            (resolution + 1) & !1
+        }
+    }
+
+    // Deepest level at which the patch grid reaches the requested vertex density.
+    pub fn max_depth(&self, map_size: u32) -> u32 {
+        if self.world_size <= 0.0 || self.patch_resolution == 0 {
+            return 0;
+        }
+
+        let finest_nodes = self.world_size * self.vertex_density(map_size) / self.patch_resolution as f32;
+        finest_nodes.max(1.0).log2().ceil() as u32
+    }
+}
+
+#[derive(Component)]
+pub struct TerrainPatch;
+
+#[derive(Resource)]
+pub struct MountainTerrain {
+    pub material: Handle<MountainMaterial>,
+    pub pbr_material: Handle<MountainPbrMaterial>,
+    mesh: Handle<Mesh>,
+    resolution: u32,
+    shading: MountainShading,
+    patches: Vec<Entity>,
+}
+
+impl MountainTerrain {
+    fn apply_shading(&self, patch: &mut EntityCommands) {
+        match self.shading {
+            MountainShading::Unlit => patch.remove::<Handle<MountainPbrMaterial>>().insert(self.material.clone()),
+            MountainShading::Pbr => patch.remove::<Handle<MountainMaterial>>().insert(self.pbr_material.clone()),
+        };
+    }
+}
+
+pub fn create_patch_mesh(resolution: u32) -> Mesh {
+    let side_vert_count = resolution + 1;
+
+    let mut positions = vec![];
+    for z in 0..=resolution {
+        for x in 0..=resolution {
+            positions.push(Vec3::new(
+                x as f32 / resolution as f32 - 0.5,
+                0.0,
+                z as f32 / resolution as f32 - 0.5,
+            ));
+        }
+    }
+
+    let mut indices = Vec::with_capacity((resolution * resolution * 6) as usize);
+    for z in 0..resolution {
+        for x in 0..resolution {
+            let vi = z * side_vert_count + x;
+            indices.extend([vi, vi + side_vert_count, vi + side_vert_count + 1]);
+            indices.extend([vi, vi + side_vert_count + 1, vi + 1]);
+        }
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::RENDER_WORLD);
+    mesh.insert_indices(Indices::U32(indices));
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![Vec3::Y; positions.len()]);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh
+}
+
+fn setup_terrain(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<MountainMaterial>>,
+    mut pbr_materials: ResMut<Assets<MountainPbrMaterial>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    settings: Res<MountainTerrainSettings>,
+) {
+    commands.insert_resource(MountainTerrain {
+        material: materials.add(MountainMaterial::default()),
+        pbr_material: pbr_materials.add(MountainPbrMaterial {
+            base: StandardMaterial::default(),
+            extension: default(),
+        }),
+        mesh: meshes.add(create_patch_mesh(settings.patch_resolution)),
+        resolution: settings.patch_resolution,
+        shading: settings.shading,
+        patches: Vec::new(),
+    });
+}
+
+fn distance_to_node(point: Vec3, center: Vec2, size: f32, height: f32) -> f32 {
+    let min = Vec3::new(center.x - size * 0.5, 0.0, center.y - size * 0.5);
+    let max = Vec3::new(center.x + size * 0.5, height, center.y + size * 0.5);
+    point.distance(point.clamp(min, max))
+}
+
+fn select_nodes(
+    camera: Vec3,
+    center: Vec2,
+    size: f32,
+    depth: u32,
+    height: f32,
+    settings: &MountainTerrainSettings,
+    nodes: &mut Vec<(Vec2, f32)>,
+) {
+    if depth == 0 || distance_to_node(camera, center, size, height) >= size * settings.lod_distance() {
+        nodes.push((center, size));
+        return;
+    }
+
+    let quarter = size * 0.25;
+    for offset in [Vec2::new(-1.0, -1.0), Vec2::new(1.0, -1.0), Vec2::new(-1.0, 1.0), Vec2::new(1.0, 1.0)] {
+        select_nodes(camera, center + offset * quarter, size * 0.5, depth - 1, height, settings, nodes);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_terrain(
+    mut commands: Commands,
+    mut terrain: ResMut<MountainTerrain>,
+    mut patches: Query<(&mut Transform, &mut Visibility, &mut Aabb), With<TerrainPatch>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut settings: ResMut<MountainTerrainSettings>,
+    materials: Res<Assets<MountainMaterial>>,
+    compute_settings: Res<MountainComputeSettings>,
+    cameras: Query<&GlobalTransform, With<PanOrbitCamera>>,
+) {
+    let valid_resolution = settings.valid_patch_resolution();
+    if settings.patch_resolution != valid_resolution {
+        settings.patch_resolution = valid_resolution;
+    }
+    if settings.patch_resolution != terrain.resolution {
+        terrain.resolution = settings.patch_resolution;
+        meshes.insert(&terrain.mesh, create_patch_mesh(terrain.resolution));
+    }
+    if settings.shading != terrain.shading {
+        terrain.shading = settings.shading;
+        for &patch in terrain.patches.iter() {
+            terrain.apply_shading(&mut commands.entity(patch));
+        }
+    }
+
+    let Ok(camera) = cameras.get_single() else { return };
+    let Some(material) = materials.get(&terrain.material) else { return };
+    let height = material.settings.terrain_height;
+
+    let mut nodes = Vec::new();
+    if settings.world_size <= 0.0 {
+        return;
+    }
+
+    let depth = settings.max_depth(compute_settings.map_size).min(MAX_LOD_DEPTH);
+    select_nodes(camera.translation(), Vec2::ZERO, settings.world_size, depth, height, &settings, &mut nodes);
+
+    let aabb = Aabb::from_min_max(Vec3::new(-0.5, 0.0, -0.5), Vec3::new(0.5, height, 0.5));
+
+    while terrain.patches.len() < nodes.len() {
+        let mut patch = commands.spawn((
+            terrain.mesh.clone(),
+            SpatialBundle {
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+            aabb,
+            TerrainPatch,
+        ));
+        terrain.apply_shading(&mut patch);
+        let patch = patch.id();
+        terrain.patches.push(patch);
+    }
+
+    for (i, &entity) in terrain.patches.iter().enumerate() {
+        let Ok((mut transform, mut visibility, mut patch_aabb)) = patches.get_mut(entity) else { continue };
+
+        if let Some(&(center, size)) = nodes.get(i) {
+            *transform = Transform::from_xyz(center.x, 0.0, center.y).with_scale(Vec3::new(size, 1.0, size));
+            *visibility = Visibility::Visible;
+            *patch_aabb = aabb;
+        } else {
+            *visibility = Visibility::Hidden;
+        }
+    }
+}
+
+pub struct MountainTerrainPlugin;
+
+impl Plugin for MountainTerrainPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<MountainTerrainSettings>()
+            .register_type::<MountainTerrainSettings>()
+            .add_systems(Startup, setup_terrain)
+            .add_systems(Update, update_terrain);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select(camera: Vec3, lod_distance: f32) -> Vec<(Vec2, f32)> {
+        let settings = MountainTerrainSettings { lod_distance, ..default() };
+        let mut nodes = Vec::new();
+        select_nodes(camera, Vec2::ZERO, settings.world_size, 6, 10.0, &settings, &mut nodes);
+        nodes
+    }
+
+    fn touching(a: (Vec2, f32), b: (Vec2, f32)) -> bool {
+        let gap = (a.0 - b.0).abs() - Vec2::splat((a.1 + b.1) * 0.5);
+        gap.max_element().abs() < 1e-3 && gap.min_element() < -1e-3
+    }
+
+    #[test]
+    fn far_camera_selects_the_root() {
+        assert_eq!(select(Vec3::new(0.0, 10_000.0, 0.0), 2.0), vec![(Vec2::ZERO, 256.0)]);
+    }
+
+    #[test]
+    fn selected_nodes_cover_the_terrain() {
+        for camera in [Vec3::ZERO, Vec3::new(100.0, 5.0, -60.0), Vec3::new(300.0, 50.0, 300.0)] {
+            let nodes = select(camera, 2.0);
+            let area: f32 = nodes.iter().map(|(_, size)| size * size).sum();
+            assert!((area - 256.0 * 256.0).abs() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn camera_gets_the_finest_nodes() {
+        let camera = Vec3::new(10.0, 1.0, 10.0);
+        let nodes = select(camera, 2.0);
+        let (_, size) = nodes.iter().find(|(center, size)| (camera.xz() - *center).abs().max_element() <= size * 0.5).unwrap();
+        assert_eq!(*size, 256.0 / 64.0);
+    }
+
+    #[test]
+    fn neighbours_differ_by_at_most_one_lod() {
+        // Below the minimum, which gets clamped.
+        for lod_distance in [0.5, 2.0, 3.0] {
+            let nodes = select(Vec3::new(30.0, 2.0, -70.0), lod_distance);
+            for &a in nodes.iter() {
+                for &b in nodes.iter().filter(|&&b| touching(a, b)) {
+                    assert!(a.1 / b.1 <= 2.0 && b.1 / a.1 <= 2.0, "{:?} next to {:?} at {}", a, b, lod_distance);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn matched_texels_land_one_vertex_per_texel() {
+        for patch_resolution in [2, 50, 64, 100, 128, 1000] {
+            let mut settings = MountainTerrainSettings { patch_resolution, ..default() };
+            settings.patch_resolution = settings.valid_patch_resolution();
+
+            let depth = settings.max_depth(4096);
+            assert_eq!(settings.patch_resolution << depth, 4096, "patch resolution {}", patch_resolution);
+        }
+    }
+
+    #[test]
+    fn unmatched_patch_resolution_is_even() {
+        let settings = MountainTerrainSettings { match_texels: false, patch_resolution: 49, ..default() };
+        assert_eq!(settings.valid_patch_resolution(), 50);
+
+        let settings = MountainTerrainSettings { match_texels: false, patch_resolution: 0, ..default() };
+        assert_eq!(settings.valid_patch_resolution(), 2);
+    }
+
+    #[test]
+    fn max_depth_rejects_degenerate_settings() {
+        let settings = MountainTerrainSettings { world_size: 0.0, ..default() };
+        assert_eq!(settings.max_depth(4096), 0);
+
+        let settings = MountainTerrainSettings { patch_resolution: 0, ..default() };
+        assert_eq!(settings.max_depth(4096), 0);
+    }
+}
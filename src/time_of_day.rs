@@ -0,0 +1,288 @@
+use std::{f32::consts::PI, path::PathBuf};
+
+use bevy::{prelude::*, render::{camera::RenderTarget, render_resource::TextureFormat}};
+use bevy_image_export::{ImageExportBundle, ImageExportSettings, ImageExportSource};
+use bevy_panorbit_camera::PanOrbitCamera;
+
+use crate::{
+    compute::{
+        stage::{MountainComputeQueue, SHADOW_STAGE},
+        uniforms::{invalidate_derived_maps, MountainComputeSettings, RegenerateShadows},
+    },
+    export::capture::{create_target, CAPTURE_DIR},
+};
+
+// Gives the sequence camera time to get its pipelines specialized before the first frame is saved.
+const SEQUENCE_WARMUP_FRAMES: u32 = 8;
+
+#[derive(Resource, Reflect, Clone)]
+#[reflect(Resource)]
+pub struct MountainTimeOfDay {
+    pub playing: bool,
+    // Position along the solar arc, 0 at sunrise and 1 at sunset.
+    pub time: f32,
+    // Fraction of the arc covered per second while playing.
+    pub speed: f32,
+    // Radians from +X towards +Z.
+    pub sunrise_azimuth: f32,
+    pub sunset_azimuth: f32,
+    // Sun elevation in radians at both ends and at the top of the arc.
+    pub horizon_elevation: f32,
+    pub noon_elevation: f32,
+    // Orbit camera yaw in radians added over the whole arc, for turntables.
+    pub turntable: f32,
+    pub sequence_frames: u32,
+    pub sequence_size: UVec2,
+}
+
+impl Default for MountainTimeOfDay {
+    fn default() -> Self {
+        Self {
+            playing: false,
+            time: 0.5,
+            speed: 0.05,
+            sunrise_azimuth: 0.0,
+            sunset_azimuth: PI,
+            horizon_elevation: 0.05,
+            noon_elevation: 1.2,
+            turntable: 0.0,
+            sequence_frames: 120,
+            sequence_size: UVec2::new(1920, 1080),
+        }
+    }
+}
+
+impl MountainTimeOfDay {
+    pub fn sun_direction(&self) -> Vec3 {
+        let azimuth = self.sunrise_azimuth + (self.sunset_azimuth - self.sunrise_azimuth) * self.time;
+        let elevation = self.horizon_elevation + (self.noon_elevation - self.horizon_elevation) * (self.time * PI).sin();
+
+        Vec3::new(elevation.cos() * azimuth.cos(), elevation.sin(), elevation.cos() * azimuth.sin())
+    }
+}
+
+#[derive(Event)]
+pub struct RecordSunSequence;
+
+#[derive(Resource)]
+pub struct MountainSunSequence {
+    camera: Entity,
+    image: Handle<Image>,
+    dir: PathBuf,
+    frames: u32,
+    export: Option<Entity>,
+}
+
+fn next_sequence_dir() -> PathBuf {
+    (0..)
+        .map(|i| PathBuf::from(CAPTURE_DIR).join(format!("sequence_{:03}", i)))
+        .find(|dir| !dir.exists())
+        .unwrap()
+}
+
+fn turn_cameras(cameras: &mut Query<&mut PanOrbitCamera>, yaw: f32) {
+    if yaw == 0.0 {
+        return;
+    }
+
+    for mut camera in cameras.iter_mut() {
+        camera.target_yaw += yaw;
+        if let Some(current) = camera.yaw.as_mut() {
+            *current += yaw;
+        }
+        camera.force_update = true;
+    }
+}
+
+pub fn advance_time_of_day(
+    time: Res<Time>,
+    mut time_of_day: ResMut<MountainTimeOfDay>,
+    sequence: Option<Res<MountainSunSequence>>,
+    mut cameras: Query<&mut PanOrbitCamera>,
+) {
+    if !time_of_day.playing || sequence.is_some() {
+        return;
+    }
+
+    let delta = time_of_day.speed * time.delta_seconds();
+    time_of_day.time = (time_of_day.time + delta).rem_euclid(1.0);
+    turn_cameras(&mut cameras, delta * time_of_day.turntable);
+}
+
+pub fn start_sun_sequence(
+    mut commands: Commands,
+    mut evr: EventReader<RecordSunSequence>,
+    mut images: ResMut<Assets<Image>>,
+    sequence: Option<Res<MountainSunSequence>>,
+    time_of_day: Res<MountainTimeOfDay>,
+    cameras: Query<(Entity, &Camera, &Projection), With<PanOrbitCamera>>,
+) {
+    if evr.read().count() == 0 || sequence.is_some() {
+        return;
+    }
+
+    let Ok((main_camera, camera, projection)) = cameras.get_single() else {
+        warn!("Sun sequence needs a single orbit camera");
+        return;
+    };
+
+    let dir = next_sequence_dir();
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        error!("Failed to create sequence directory {}: {}", dir.display(), e);
+        return;
+    }
+
+    let image = images.add(create_target(time_of_day.sequence_size.max(UVec2::ONE), TextureFormat::Rgba8UnormSrgb));
+    let sequence_camera = commands.spawn(Camera3dBundle {
+        camera: Camera {
+            order: -1,
+            target: RenderTarget::Image(image.clone()),
+            clear_color: camera.clear_color.clone(),
+            ..default()
+        },
+        projection: projection.clone(),
+        ..default()
+    }).set_parent(main_camera).id();
+
+    info!("Recording {} frame sun sequence to {}", time_of_day.sequence_frames.max(2), dir.display());
+    commands.insert_resource(MountainSunSequence { camera: sequence_camera, image, dir, frames: 0, export: None });
+}
+
+pub fn update_sun_sequence(
+    mut commands: Commands,
+    sequence: Option<ResMut<MountainSunSequence>>,
+    mut time_of_day: ResMut<MountainTimeOfDay>,
+    mut export_sources: ResMut<Assets<ImageExportSource>>,
+    mut cameras: Query<&mut PanOrbitCamera>,
+    queue: Res<MountainComputeQueue>,
+) {
+    let Some(mut sequence) = sequence else { return };
+
+    if let Some(export) = sequence.export.take() {
+        commands.entity(export).despawn();
+    }
+
+    sequence.frames += 1;
+    if sequence.frames < SEQUENCE_WARMUP_FRAMES {
+        return;
+    }
+
+    // Each step takes two frames, one to move the sun and re-bake the shadows and one to save the render,
+    // which waits for the re-bake to be scheduled.
+    let frame = sequence.frames - SEQUENCE_WARMUP_FRAMES;
+    let steps = time_of_day.sequence_frames.max(2);
+    let step = frame / 2;
+
+    if step == steps {
+        commands.entity(sequence.camera).despawn_recursive();
+        info!("Saved sun sequence to {}", sequence.dir.display());
+        commands.remove_resource::<MountainSunSequence>();
+        return;
+    }
+
+    if frame % 2 == 0 {
+        let time = step as f32 / (steps - 1) as f32;
+        turn_cameras(&mut cameras, (time - time_of_day.time) * time_of_day.turntable);
+        time_of_day.time = time;
+    } else if queue.is_pending(SHADOW_STAGE) {
+        sequence.frames -= 1;
+    } else {
+        sequence.export = Some(commands.spawn(ImageExportBundle {
+            source: export_sources.add(sequence.image.clone()),
+            settings: ImageExportSettings {
+                output_dir: sequence.dir.to_string_lossy().into(),
+                extension: "png".into(),
+            },
+        }).id());
+    }
+}
+
+// Only moves the sun when the time or the arc changes, so playback and sequence settings leave a
+// hand set direction alone.
+pub fn apply_time_of_day(
+    time_of_day: Res<MountainTimeOfDay>,
+    mut settings: ResMut<MountainComputeSettings>,
+    mut shadow_evw: EventWriter<RegenerateShadows>,
+    mut applied: Local<Option<Vec3>>,
+) {
+    let sun_direction = time_of_day.sun_direction();
+    let Some(last) = applied.replace(sun_direction) else { return };
+    if last == sun_direction {
+        return;
+    }
+
+    settings.sun_direction = sun_direction;
+    shadow_evw.send(RegenerateShadows);
+}
+
+pub struct MountainTimeOfDayPlugin;
+
+impl Plugin for MountainTimeOfDayPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<MountainTimeOfDay>()
+            .add_event::<RecordSunSequence>()
+            .add_systems(Update, (
+                advance_time_of_day,
+                start_sun_sequence,
+                update_sun_sequence,
+                apply_time_of_day,
+            ).chain().before(invalidate_derived_maps));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn elevation(direction: Vec3) -> f32 {
+        direction.y.asin()
+    }
+
+    fn azimuth(direction: Vec3) -> f32 {
+        direction.z.atan2(direction.x)
+    }
+
+    #[test]
+    fn sun_direction_is_normalized() {
+        let mut time_of_day = MountainTimeOfDay::default();
+
+        for i in 0..=10 {
+            time_of_day.time = i as f32 / 10.0;
+            assert!((time_of_day.sun_direction().length() - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn sun_rises_and_sets_at_the_arc_ends() {
+        let mut time_of_day = MountainTimeOfDay {
+            sunrise_azimuth: 0.25,
+            sunset_azimuth: 2.5,
+            ..default()
+        };
+
+        time_of_day.time = 0.0;
+        let sunrise = time_of_day.sun_direction();
+        assert!((azimuth(sunrise) - 0.25).abs() < 1e-5);
+        assert!((elevation(sunrise) - time_of_day.horizon_elevation).abs() < 1e-5);
+
+        time_of_day.time = 1.0;
+        let sunset = time_of_day.sun_direction();
+        assert!((azimuth(sunset) - 2.5).abs() < 1e-5);
+        assert!((elevation(sunset) - time_of_day.horizon_elevation).abs() < 1e-5);
+    }
+
+    #[test]
+    fn sun_peaks_halfway_along_the_arc() {
+        let time_of_day = MountainTimeOfDay::default();
+        let noon = time_of_day.sun_direction();
+
+        assert!((elevation(noon) - time_of_day.noon_elevation).abs() < 1e-5);
+        assert!((azimuth(noon) - PI * 0.5).abs() < 1e-5);
+
+        let morning = MountainTimeOfDay { time: 0.25, ..default() }.sun_direction();
+        let evening = MountainTimeOfDay { time: 0.75, ..default() }.sun_direction();
+        assert!((elevation(morning) - elevation(evening)).abs() < 1e-5);
+        assert!(elevation(morning) < elevation(noon));
+    }
+}